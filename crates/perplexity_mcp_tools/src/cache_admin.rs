@@ -0,0 +1,170 @@
+use std::{io::Cursor, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use context_server::{Tool, ToolContent, ToolExecutor};
+use serde_json::{Value, json};
+use similarity_cache::{
+    CacheQuery, CacheSelector, SimilarityCache, export_recent_jsonl, import_jsonl,
+};
+
+const DEFAULT_LIMIT: usize = 20;
+
+pub fn format_cache_entry(entry: &CacheQuery) -> String {
+    let text = entry.text.replace('\n', " ");
+    let text = if text.chars().count() > 80 {
+        format!("{}...", text.chars().take(77).collect::<String>())
+    } else {
+        text
+    };
+
+    format!(
+        "{}  {}  {}  {}",
+        entry.id(),
+        entry.action,
+        entry.created_at.format("%Y-%m-%d %H:%M:%S"),
+        text
+    )
+}
+
+fn selector_from_arguments(args: &Value) -> Result<CacheSelector> {
+    if let Some(id) = args.get("id").and_then(|v| v.as_str()) {
+        return Ok(CacheSelector::Id(id.to_string()));
+    }
+    if let Some(action) = args.get("action").and_then(|v| v.as_str()) {
+        return Ok(CacheSelector::Action(action.to_string()));
+    }
+    if let Some(days) = args.get("older_than_days").and_then(|v| v.as_u64()) {
+        return CacheSelector::older_than_days(days);
+    }
+
+    Err(anyhow!(
+        "Deleting requires one of: id, action or older_than_days"
+    ))
+}
+
+pub struct CacheAdminTool {
+    similarity_cache: Arc<dyn SimilarityCache>,
+}
+
+impl CacheAdminTool {
+    pub fn new(similarity_cache: Arc<dyn SimilarityCache>) -> Self {
+        Self { similarity_cache }
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for CacheAdminTool {
+    async fn execute(&self, arguments: Option<Value>) -> Result<Vec<ToolContent>> {
+        log::debug!("Executing CacheAdminTool");
+        let args = arguments.ok_or_else(|| anyhow!("Missing arguments"))?;
+
+        let operation = args
+            .get("operation")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Missing or invalid operation"))?;
+
+        let limit = args
+            .get("limit")
+            .and_then(|v| v.as_u64())
+            .map(|limit| limit as usize)
+            .unwrap_or(DEFAULT_LIMIT);
+
+        let cache = self.similarity_cache.as_ref();
+
+        let text = match operation {
+            "list" => {
+                let entries = cache.entries().await?;
+                let mut lines = entries
+                    .iter()
+                    .rev()
+                    .take(limit)
+                    .map(format_cache_entry)
+                    .collect::<Vec<_>>();
+                lines.insert(0, format!("{} cached entries", entries.len()));
+                lines.join("\n")
+            }
+            "search" => {
+                let text = args
+                    .get("text")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow!("Missing or invalid text"))?;
+
+                similarity_cache::search(cache, text, limit)
+                    .await?
+                    .iter()
+                    .map(|(score, entry)| format!("{:.3}  {}", score, format_cache_entry(entry)))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            "delete" => {
+                let selector = selector_from_arguments(&args)?;
+                let deleted = cache.delete(&selector).await?;
+                format!("Deleted {} cached entries", deleted)
+            }
+            "clear" => {
+                let deleted = cache.delete(&CacheSelector::All).await?;
+                format!("Deleted {} cached entries", deleted)
+            }
+            "export" => {
+                // Bulk exports are left to the `cache export` command
+                let mut buffer = Vec::new();
+                export_recent_jsonl(cache, &mut buffer, limit).await?;
+                String::from_utf8(buffer)?
+            }
+            "import" => {
+                let jsonl = args
+                    .get("jsonl")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow!("Missing or invalid jsonl"))?;
+                let imported = import_jsonl(cache, Cursor::new(jsonl)).await?;
+                format!("Imported {} cached entries", imported)
+            }
+            other => return Err(anyhow!("Unknown cache operation: {}", other)),
+        };
+
+        Ok(vec![ToolContent::Text { text }])
+    }
+
+    fn to_tool(&self) -> Tool {
+        Tool {
+            name: "cache_admin".into(),
+            description: Some("Inspect and manage the cache of previous Perplexity answers".into()),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "operation": {
+                        "type": "string",
+                        "description": "The operation to perform",
+                        "enum": ["list", "search", "delete", "clear", "export", "import"]
+                    },
+                    "text": {
+                        "type": "string",
+                        "description": "For search: the text to look for"
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "For list, search and export: maximum number of entries to return"
+                    },
+                    "id": {
+                        "type": "string",
+                        "description": "For delete: the id of the entry to delete"
+                    },
+                    "action": {
+                        "type": "string",
+                        "description": "For delete: delete every entry cached for this tool"
+                    },
+                    "older_than_days": {
+                        "type": "integer",
+                        "description": "For delete: delete entries older than this many days"
+                    },
+                    "jsonl": {
+                        "type": "string",
+                        "description": "For import: cache entries as JSON lines, as produced by export"
+                    }
+                },
+                "required": ["operation"]
+            }),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use serde_json::{Value, json};
+use similarity_cache::Similarity;
+
+pub(crate) const DEFAULT_THRESHOLD: f32 = 0.95;
+const PREFERRED_THRESHOLD: f32 = 0.8;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum CachePolicy {
+    Disabled,
+    ExactOnly,
+    Fuzzy {
+        threshold: f32,
+    },
+    // Like fuzzy, but answers older than `max_age_seconds` are served at once
+    // while a fresh one is fetched in the background
+    StaleWhileRevalidate {
+        threshold: f32,
+        max_age_seconds: u64,
+    },
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy::Fuzzy {
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+impl CachePolicy {
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, CachePolicy::Disabled)
+    }
+
+    pub fn accepts(&self, similarity: &Similarity) -> bool {
+        match self {
+            CachePolicy::Disabled => false,
+            CachePolicy::ExactOnly => similarity.reason.exact_text,
+            CachePolicy::Fuzzy { threshold }
+            | CachePolicy::StaleWhileRevalidate { threshold, .. } => {
+                similarity.reason.exact_text || similarity.score >= *threshold
+            }
+        }
+    }
+
+    pub fn is_stale(&self, similarity: &Similarity) -> bool {
+        match self {
+            CachePolicy::StaleWhileRevalidate {
+                max_age_seconds, ..
+            } => (Utc::now() - similarity.query.created_at).num_seconds() > *max_age_seconds as i64,
+            _ => false,
+        }
+    }
+
+    // The policy applied when a caller asks to prefer cached answers, or when
+    // offline. A disabled cache stays disabled
+    pub(crate) fn preferring(&self) -> Self {
+        match self {
+            CachePolicy::Disabled => CachePolicy::Disabled,
+            CachePolicy::Fuzzy { threshold } => CachePolicy::Fuzzy {
+                threshold: threshold.min(PREFERRED_THRESHOLD),
+            },
+            CachePolicy::StaleWhileRevalidate {
+                threshold,
+                max_age_seconds,
+            } => CachePolicy::StaleWhileRevalidate {
+                threshold: threshold.min(PREFERRED_THRESHOLD),
+                max_age_seconds: *max_age_seconds,
+            },
+            CachePolicy::ExactOnly => CachePolicy::Fuzzy {
+                threshold: PREFERRED_THRESHOLD,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheDirective {
+    // Neither read from nor write to the cache
+    Bypass,
+    // Skip the cached answer, but store the fresh one
+    Refresh,
+    // Reuse a close cached answer even when the tool policy would not, unless
+    // the tool does not cache at all
+    Prefer,
+}
+
+impl CacheDirective {
+    pub fn from_arguments(arguments: &Value) -> Result<Option<Self>> {
+        match arguments.get("cache").and_then(|v| v.as_str()) {
+            None => Ok(None),
+            Some("bypass") => Ok(Some(CacheDirective::Bypass)),
+            Some("refresh") => Ok(Some(CacheDirective::Refresh)),
+            Some("prefer") => Ok(Some(CacheDirective::Prefer)),
+            Some(other) => Err(anyhow!("Invalid cache directive: {}", other)),
+        }
+    }
+
+    pub fn input_schema() -> Value {
+        json!({
+            "type": "string",
+            "description": "Optional: Cache behaviour for this call (bypass: always ask Perplexity and do not store the answer, refresh: ask Perplexity and replace the cached answer, prefer: reuse a close cached answer when available)",
+            "enum": ["bypass", "refresh", "prefer"]
+        })
+    }
+}
+
+// Resolves the policy used to look up the cache, `None` meaning no lookup at
+// all. No directive overrides a disabled policy
+pub(crate) fn read_policy(
+    policy: CachePolicy,
+    directive: Option<CacheDirective>,
+) -> Option<CachePolicy> {
+    if !policy.is_enabled() {
+        return None;
+    }
+
+    match directive {
+        Some(CacheDirective::Bypass) | Some(CacheDirective::Refresh) => None,
+        Some(CacheDirective::Prefer) => Some(policy.preferring()),
+        None => Some(policy),
+    }
+}
+
+pub(crate) fn should_store(policy: CachePolicy, directive: Option<CacheDirective>) -> bool {
+    policy.is_enabled() && directive != Some(CacheDirective::Bypass)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use similarity_cache::{CacheQuery, MatchReason};
+
+    use super::*;
+
+    const DIRECTIVES: [Option<CacheDirective>; 4] = [
+        None,
+        Some(CacheDirective::Bypass),
+        Some(CacheDirective::Refresh),
+        Some(CacheDirective::Prefer),
+    ];
+
+    fn similarity(score: f32, exact_text: bool, age_seconds: i64) -> Similarity {
+        Similarity {
+            query: CacheQuery {
+                action: "search".into(),
+                text: "tokio".into(),
+                params: None,
+                embedding: Vec::new(),
+                results: Value::Null,
+                created_at: Utc::now() - chrono::Duration::seconds(age_seconds),
+            },
+            score,
+            reason: MatchReason {
+                action: "search".into(),
+                params: Default::default(),
+                exact_text,
+            },
+        }
+    }
+
+    #[test]
+    fn disabled_wins_over_every_directive() {
+        for directive in DIRECTIVES {
+            assert_eq!(read_policy(CachePolicy::Disabled, directive), None);
+            assert!(!should_store(CachePolicy::Disabled, directive));
+        }
+        assert_eq!(CachePolicy::Disabled.preferring(), CachePolicy::Disabled);
+    }
+
+    #[test]
+    fn directives_resolve_against_an_enabled_policy() {
+        let policy = CachePolicy::default();
+
+        assert_eq!(read_policy(policy, None), Some(policy));
+        assert_eq!(read_policy(policy, Some(CacheDirective::Bypass)), None);
+        assert_eq!(read_policy(policy, Some(CacheDirective::Refresh)), None);
+        assert_eq!(
+            read_policy(policy, Some(CacheDirective::Prefer)),
+            Some(CachePolicy::Fuzzy {
+                threshold: PREFERRED_THRESHOLD
+            })
+        );
+
+        assert!(should_store(policy, None));
+        assert!(!should_store(policy, Some(CacheDirective::Bypass)));
+        assert!(should_store(policy, Some(CacheDirective::Refresh)));
+        assert!(should_store(policy, Some(CacheDirective::Prefer)));
+    }
+
+    #[test]
+    fn preferring_only_relaxes_thresholds() {
+        assert_eq!(
+            CachePolicy::Fuzzy { threshold: 0.7 }.preferring(),
+            CachePolicy::Fuzzy { threshold: 0.7 }
+        );
+        assert_eq!(
+            CachePolicy::ExactOnly.preferring(),
+            CachePolicy::Fuzzy {
+                threshold: PREFERRED_THRESHOLD
+            }
+        );
+        assert_eq!(
+            CachePolicy::StaleWhileRevalidate {
+                threshold: 0.95,
+                max_age_seconds: 60
+            }
+            .preferring(),
+            CachePolicy::StaleWhileRevalidate {
+                threshold: PREFERRED_THRESHOLD,
+                max_age_seconds: 60
+            }
+        );
+    }
+
+    #[test]
+    fn policies_accept_by_score_and_exact_text() {
+        let fuzzy = CachePolicy::default();
+        assert!(fuzzy.accepts(&similarity(0.96, false, 0)));
+        assert!(!fuzzy.accepts(&similarity(0.9, false, 0)));
+        assert!(fuzzy.accepts(&similarity(0.1, true, 0)));
+
+        assert!(CachePolicy::ExactOnly.accepts(&similarity(1.0, true, 0)));
+        assert!(!CachePolicy::ExactOnly.accepts(&similarity(0.99, false, 0)));
+        assert!(!CachePolicy::Disabled.accepts(&similarity(1.0, true, 0)));
+    }
+
+    #[test]
+    fn answers_go_stale_after_max_age() {
+        let policy = CachePolicy::StaleWhileRevalidate {
+            threshold: 0.95,
+            max_age_seconds: 60,
+        };
+        assert!(!policy.is_stale(&similarity(1.0, true, 30)));
+        assert!(policy.is_stale(&similarity(1.0, true, 120)));
+        assert!(!CachePolicy::default().is_stale(&similarity(1.0, true, 120)));
+    }
+
+    #[test]
+    fn directives_parse_from_arguments() {
+        assert_eq!(CacheDirective::from_arguments(&json!({})).ok(), Some(None));
+        assert_eq!(
+            CacheDirective::from_arguments(&json!({ "cache": "prefer" })).ok(),
+            Some(Some(CacheDirective::Prefer))
+        );
+        assert!(CacheDirective::from_arguments(&json!({ "cache": "always" })).is_err());
+    }
+}
@@ -1,13 +1,54 @@
-use std::{env, sync::Arc};
+mod cache_admin;
+mod cache_policy;
+mod prompts;
+mod single_flight;
+mod telemetry;
+mod tool_call;
+
+use std::{
+    env,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Result, anyhow};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use context_server::{Tool, ToolContent, ToolExecutor};
 use http_client::{HttpClient, Request, RequestBuilderExt, ResponseAsyncBodyExt};
 use indoc::formatdoc;
+use opentelemetry::{Context, KeyValue, trace::FutureExt};
 use serde_json::{Value, json};
-use similarity_cache::{CacheQuery, PassthroughSimilarityCache, SimilarityCache};
-use usage_reporter::{NoopUsageReporter, Usage, UsageReport, UsageReporter};
+use similarity_cache::{
+    CacheQuery, CacheSelector, PassthroughSimilarityCache, Similarity, SimilarityCache, embed,
+};
+use usage_reporter::{
+    BudgetExceeded, CacheStatus, NoopUsageReporter, PricingTable, Usage, UsageEvent, UsageReport,
+    UsageReporter,
+};
+
+pub use crate::cache_admin::{CacheAdminTool, format_cache_entry};
+pub use crate::cache_policy::{CacheDirective, CachePolicy};
+pub use crate::prompts::{ResearchPrompt, research_prompts};
+pub use crate::tool_call::{ToolCallContext, ToolCallMeta, with_tool_call};
+
+use crate::{cache_policy::DEFAULT_THRESHOLD, single_flight::SingleFlight, tool_call::ToolError};
+
+static IN_FLIGHT: LazyLock<SingleFlight> = LazyLock::new(SingleFlight::default);
+
+const PERPLEXITY_API_URL: &str = "https://api.perplexity.ai/chat/completions";
+
+const MAX_ATTEMPTS: u32 = 3;
+// Doubled after each failed attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Rate limited, or the API having trouble, which is usually over soon
+fn is_retryable(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status) && status != 501
+}
 
 fn format_response_with_references(response_body: &Value) -> Result<String> {
     log::debug!("Formatting response with references");
@@ -40,45 +81,482 @@ fn format_response_with_references(response_body: &Value) -> Result<String> {
     Ok(content)
 }
 
+fn format_response(response: &PerplexityResponse, show_cost: bool) -> Result<String> {
+    let content = format_response_with_references(&response.body)?;
+    if let Some(citations) = response.body.get("citations") {
+        tool_call::set_meta("citations", citations.clone());
+    }
+
+    match response.cost.filter(|_| show_cost) {
+        Some(cost) if cost.saved > 0.0 => Ok(format!(
+            "{}\n\nEstimated cost: ${:.4} (answered without a new Perplexity call, saving ${:.4})",
+            content, cost.spent, cost.saved
+        )),
+        Some(cost) => Ok(format!("{}\n\nEstimated cost: ${:.4}", content, cost.spent)),
+        None => Ok(content),
+    }
+}
+
+fn parse_usage(response_body: &Value) -> Option<(String, Usage)> {
+    let usage = response_body.get("usage")?;
+    let model = response_body.get("model").and_then(|m| m.as_str())?;
+    let optional_tokens = |key: &str| usage.get(key).and_then(|t| t.as_u64()).unwrap_or(0);
+
+    Some((
+        model.to_string(),
+        Usage {
+            completion_tokens: usage.get("completion_tokens").and_then(|t| t.as_u64())?,
+            prompt_tokens: usage.get("prompt_tokens").and_then(|t| t.as_u64())?,
+            total_tokens: usage.get("total_tokens").and_then(|t| t.as_u64())?,
+            citation_tokens: optional_tokens("citation_tokens"),
+            reasoning_tokens: optional_tokens("reasoning_tokens"),
+            num_search_queries: optional_tokens("num_search_queries"),
+            search_context_size: usage
+                .get("search_context_size")
+                .and_then(|size| serde_json::from_value(size.clone()).ok()),
+        },
+    ))
+}
+
+async fn report_usage(
+    usage_reporter: &Arc<dyn UsageReporter>,
+    pricing: &PricingTable,
+    action: &str,
+    context: &ToolCallContext,
+    started: Instant,
+    response: &PerplexityResponse,
+) -> Option<EstimatedCost> {
+    let (model, usage) = parse_usage(&response.body)?;
+
+    // A cached answer costs nothing, but still accounts for the tokens it saved
+    let (usage, cache) = match &response.cached {
+        Some(cached) if cached.stale => (
+            Usage::default(),
+            CacheStatus::Stale {
+                score: cached.score,
+                saved: usage,
+            },
+        ),
+        Some(cached) => (
+            Usage::default(),
+            CacheStatus::Hit {
+                score: cached.score,
+                saved: usage,
+            },
+        ),
+        // Another identical request in flight already paid for this answer
+        None if response.coalesced => (Usage::default(), CacheStatus::Coalesced { saved: usage }),
+        None => (usage, CacheStatus::Miss),
+    };
+
+    // Nothing is spent on an answer that another call paid for, not even the request fee
+    let cost = match cache.saved() {
+        Some(_) => pricing.price(&model).map(|_| 0.0),
+        None => pricing.cost(&model, &usage),
+    };
+    let saved_cost = cache.saved().and_then(|saved| pricing.cost(&model, &saved));
+    if let Some(cost) = cost {
+        log::info!("Estimated cost of {} call: ${:.4}", action, cost);
+    }
+
+    telemetry::set_attributes([
+        KeyValue::new("gen_ai.request.model", model.clone()),
+        KeyValue::new("gen_ai.usage.input_tokens", usage.prompt_tokens as i64),
+        KeyValue::new("gen_ai.usage.output_tokens", usage.completion_tokens as i64),
+        KeyValue::new("perplexity_mcp.cache", cache.name()),
+        KeyValue::new("perplexity_mcp.cost_usd", cost.unwrap_or(0.0)),
+    ]);
+
+    let report = UsageReport {
+        timestamp: Utc::now(),
+        tool: action.to_string(),
+        request_id: context.request_id.clone(),
+        client_name: context.client_name.clone(),
+        model,
+        usage,
+        cache,
+        latency: started.elapsed(),
+        cost,
+        saved_cost,
+        http_attempts: response.http_attempts,
+    };
+
+    // Lets the agent weigh whether another call is worth its cost
+    tool_call::set_meta(
+        "usage",
+        json!({
+            "model": report.model,
+            "prompt_tokens": report.usage.prompt_tokens,
+            "completion_tokens": report.usage.completion_tokens,
+            "total_tokens": report.usage.total_tokens,
+            "saved_tokens": report.cache.saved().map(|saved| saved.total_tokens),
+            "cost_usd": cost,
+            "saved_cost_usd": saved_cost,
+            "cache": report.cache.name(),
+            "latency_ms": report.latency.as_millis() as u64
+        }),
+    );
+
+    if let Err(err) = usage_reporter.report(report).await {
+        log::error!("Failed to report usage: {}", err);
+    }
+
+    cost.map(|spent| EstimatedCost {
+        spent,
+        saved: saved_cost.unwrap_or(0.0),
+    })
+}
+
+async fn cache_lookup(
+    similarity_cache: &Arc<dyn SimilarityCache>,
+    usage_reporter: &Arc<dyn UsageReporter>,
+    action: &str,
+    query: CacheQuery,
+    significant_params: &[String],
+) -> Result<Vec<Similarity>> {
+    telemetry::in_span(
+        "similarity_cache lookup",
+        vec![KeyValue::new("gen_ai.tool.name", action.to_string())],
+        async {
+            let similarities = similarity_cache
+                .similarities(query, significant_params)
+                .await?;
+            let score = similarities.first().map(|closest| closest.score);
+            if let Some(score) = score {
+                telemetry::set_attributes([KeyValue::new(
+                    "perplexity_mcp.cache.score",
+                    f64::from(score),
+                )]);
+            }
+            usage_reporter.record(UsageEvent::CacheLookup {
+                tool: action,
+                score,
+            });
+            Ok(similarities)
+        },
+    )
+    .await
+}
+
+struct PerplexityRequest<'a> {
+    action: &'a str,
+    model: &'a str,
+    messages: Value,
+    // What the user asked, matched against cached answers
+    cache_text: String,
+    search_recency_filter: Option<&'a str>,
+    cache_params: Value,
+    cache_policy: CachePolicy,
+    cache_directive: Option<CacheDirective>,
+    offline: bool,
+    pricing: &'a Arc<PricingTable>,
+}
+
+// The arguments the user supplied, without the prompt template around them,
+// whose boilerplate would otherwise make every request of a tool look alike
+fn cache_text(arguments: &[&str]) -> String {
+    arguments
+        .iter()
+        .map(|argument| argument.trim())
+        .filter(|argument| !argument.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+struct CachedAnswer {
+    score: f32,
+    text: String,
+    created_at: DateTime<Utc>,
+    stale: bool,
+}
+
+fn set_cache_hit_meta(cached: &CachedAnswer, offline: bool) {
+    tool_call::set_meta(
+        "cache",
+        json!({
+            "hit": true,
+            "stale": cached.stale,
+            "offline": offline,
+            "score": cached.score,
+            "query": cached.text,
+            "age_seconds": (Utc::now() - cached.created_at).num_seconds()
+        }),
+    );
+}
+
+struct PerplexityResponse {
+    body: Value,
+    cached: Option<CachedAnswer>,
+    coalesced: bool,
+    http_attempts: u32,
+    cost: Option<EstimatedCost>,
+}
+
+#[derive(Clone, Copy)]
+struct EstimatedCost {
+    spent: f64,
+    saved: f64,
+}
+
+// The message of an error response, which is JSON unless a proxy answered
+fn api_error_message(body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|error| error["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| {
+            body.chars()
+                .take(200)
+                .collect::<String>()
+                .trim()
+                .to_string()
+        })
+}
+
+// Takes owned arguments so that it can also revalidate stale answers in the
+// background. Concurrent identical requests share a single HTTP call, which
+// is retried a few times when it fails
+async fn fetch_and_store(
+    http_client: Arc<dyn HttpClient>,
+    similarity_cache: Arc<dyn SimilarityCache>,
+    usage_reporter: &Arc<dyn UsageReporter>,
+    action: &str,
+    request_body: Value,
+    query: Option<CacheQuery>,
+) -> Result<PerplexityResponse> {
+    let key = request_body.to_string();
+    // Stays at zero when this caller joins another one's call
+    let attempts = AtomicU32::new(0);
+    let attempts_made = &attempts;
+
+    let (body, coalesced) = IN_FLIGHT
+        .run(key, || async move {
+            let api_key = env::var("PERPLEXITY_API_KEY").map_err(|_| {
+                log::error!("PERPLEXITY_API_KEY not set in environment");
+                anyhow!("PERPLEXITY_API_KEY not set in environment")
+            })?;
+
+            let model = request_body["model"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let response_json: Value = telemetry::in_span(
+                "POST chat/completions",
+                vec![
+                    KeyValue::new("http.request.method", "POST"),
+                    KeyValue::new("url.full", PERPLEXITY_API_URL),
+                    KeyValue::new("gen_ai.request.model", model),
+                ],
+                async {
+                    let mut delay = RETRY_DELAY;
+                    loop {
+                        let attempt = attempts_made.fetch_add(1, Ordering::Relaxed) + 1;
+                        let response = http_client
+                            .send(
+                                Request::builder()
+                                    .method("POST")
+                                    .uri(PERPLEXITY_API_URL)
+                                    .header("Authorization", format!("Bearer {}", api_key))
+                                    .header("Content-Type", "application/json")
+                                    .json(&request_body)?,
+                            )
+                            .await;
+                        let response = match response {
+                            Ok(response) => response,
+                            Err(err) if attempt < MAX_ATTEMPTS => {
+                                log::warn!("Perplexity API attempt {} failed: {}", attempt, err);
+                                tokio::time::sleep(delay).await;
+                                delay *= 2;
+                                continue;
+                            }
+                            Err(err) => return Err(err),
+                        };
+
+                        let status = response.status().as_u16();
+                        telemetry::set_attributes([
+                            KeyValue::new("http.response.status_code", i64::from(status)),
+                            KeyValue::new("http.request.resend_count", i64::from(attempt - 1)),
+                        ]);
+                        usage_reporter.record(UsageEvent::HttpResponse {
+                            tool: action,
+                            status,
+                        });
+                        if is_retryable(status) && attempt < MAX_ATTEMPTS {
+                            log::warn!(
+                                "Perplexity API attempt {} responded with {}, retrying",
+                                attempt,
+                                status
+                            );
+                            tokio::time::sleep(delay).await;
+                            delay *= 2;
+                            continue;
+                        }
+                        if !response.status().is_success() {
+                            let body = response.text().await.unwrap_or_default();
+                            return Err(anyhow!(
+                                "Perplexity API responded with {}: {}",
+                                status,
+                                api_error_message(&body)
+                            ));
+                        }
+
+                        return response.json().await.map_err(|err| {
+                            log::error!("Failed to parse API response: {}", err);
+                            anyhow!("{}", err.to_string())
+                        });
+                    }
+                },
+            )
+            .await?;
+
+            // Only successful answers get this far, so errors are never cached
+            if let Some(mut cached_query) = query {
+                cached_query.results = response_json.clone();
+                cached_query.created_at = Utc::now();
+                let _ = similarity_cache.store(cached_query).await;
+            }
+
+            Ok(response_json)
+        })
+        .await?;
+
+    Ok(PerplexityResponse {
+        body,
+        cached: None,
+        coalesced,
+        http_attempts: attempts.load(Ordering::Relaxed),
+        cost: None,
+    })
+}
+
+// Counts a call as in flight until dropped, however the call ends
+struct InFlightCall<'a> {
+    usage_reporter: &'a Arc<dyn UsageReporter>,
+    action: &'a str,
+}
+
+impl<'a> InFlightCall<'a> {
+    fn start(usage_reporter: &'a Arc<dyn UsageReporter>, action: &'a str) -> Self {
+        usage_reporter.record(UsageEvent::CallStarted { tool: action });
+        Self {
+            usage_reporter,
+            action,
+        }
+    }
+}
+
+impl Drop for InFlightCall<'_> {
+    fn drop(&mut self) {
+        self.usage_reporter
+            .record(UsageEvent::CallFinished { tool: self.action });
+    }
+}
+
 async fn call_perplexity_api(
     http_client: &Arc<dyn HttpClient>,
     similarity_cache: &Arc<dyn SimilarityCache>,
-    model: &str,
-    messages: Value,
-    search_recency_filter: Option<&str>,
-) -> Result<Value> {
+    usage_reporter: &Arc<dyn UsageReporter>,
+    request: PerplexityRequest<'_>,
+) -> Result<PerplexityResponse> {
+    let PerplexityRequest {
+        action,
+        model,
+        messages,
+        cache_text,
+        search_recency_filter,
+        cache_params,
+        cache_policy,
+        cache_directive,
+        offline,
+        pricing,
+    } = request;
+
+    let started = Instant::now();
+    let context = tool_call::context();
+    let _in_flight = InFlightCall::start(usage_reporter, action);
     log::debug!("Calling Perplexity API with model: {}", model);
 
+    // Every parameter that shapes the answer must match for a cached entry to be reused
+    let mut params = json!({
+        "model": model,
+        "search_recency_filter": search_recency_filter
+    });
+    if let (Some(params), Some(cache_params)) = (params.as_object_mut(), cache_params.as_object()) {
+        params.extend(cache_params.clone());
+    }
+    let significant_params = params
+        .as_object()
+        .map(|params| params.keys().cloned().collect::<Vec<String>>())
+        .unwrap_or_default();
+
     // Create a Query object for similarity cache
-    let query_embedding = vec![0.0; 1]; // Placeholder for actual embedding computation
     let query = CacheQuery {
-        action: "perplexity_api_call".to_string(),
-        text: format!("{:?}", messages),
-        params: Some(json!({
-            "model": model,
-            "search_recency_filter": search_recency_filter
-        })),
-        embedding: query_embedding,
+        action: action.to_string(),
+        embedding: embed(&cache_text),
+        text: cache_text,
+        params: Some(params),
         results: Value::Null,
+        created_at: Utc::now(),
     };
 
-    // Check similarity cache for existing results
-    let similarities = similarity_cache.similarities(query.clone()).await?;
-    if let Some(similar_query) = similarities.first() {
-        if similar_query.score > 0.95 {
-            // High similarity threshold
-            log::info!(
-                "Found cached similar response with score: {}",
-                similar_query.score
-            );
-            return Ok(similar_query.query.results.clone());
-        }
-    }
+    // Offline, the closest cached answer is all there is, however old it may be
+    if offline {
+        let similarities = cache_lookup(
+            similarity_cache,
+            usage_reporter,
+            action,
+            query,
+            &significant_params,
+        )
+        .await?;
+        let offline_policy = cache_policy.preferring();
+        let Some(similar_query) = similarities
+            .first()
+            .filter(|similarity| offline_policy.accepts(similarity))
+        else {
+            let closest = similarities
+                .first()
+                .map(|similarity| format!(" (closest match scored {:.2})", similarity.score))
+                .unwrap_or_default();
+            return Err(ToolError(format!(
+                "Offline mode: no cached answer is close enough to this {} request{}",
+                action, closest
+            ))
+            .into());
+        };
 
-    let api_key = env::var("PERPLEXITY_API_KEY").map_err(|_| {
-        log::error!("PERPLEXITY_API_KEY not set in environment");
-        anyhow!("PERPLEXITY_API_KEY not set in environment")
-    })?;
+        log::info!(
+            "Serving cached response offline with score: {} ({})",
+            similar_query.score,
+            similar_query.reason
+        );
+
+        let cached = CachedAnswer {
+            score: similar_query.score,
+            text: similar_query.query.text.clone(),
+            created_at: similar_query.query.created_at,
+            stale: cache_policy.is_stale(similar_query),
+        };
+        set_cache_hit_meta(&cached, true);
+
+        let mut response = PerplexityResponse {
+            body: similar_query.query.results.clone(),
+            cached: Some(cached),
+            coalesced: false,
+            http_attempts: 0,
+            cost: None,
+        };
+        response.cost = report_usage(
+            usage_reporter,
+            pricing,
+            action,
+            &context,
+            started,
+            &response,
+        )
+        .await;
+
+        return Ok(response);
+    }
 
     let mut request_body = json!({
         "model": model,
@@ -90,37 +568,174 @@ async fn call_perplexity_api(
         request_body["search_recency_filter"] = json!(filter);
     }
 
-    let response = http_client
-        .send(
-            Request::builder()
-                .method("POST")
-                .uri("https://api.perplexity.ai/chat/completions")
-                .header("Authorization", format!("Bearer {}", api_key))
-                .header("Content-Type", "application/json")
-                .json(request_body)?,
+    // Check similarity cache for existing results
+    if let Some(read_policy) = cache_policy::read_policy(cache_policy, cache_directive) {
+        let similarities = cache_lookup(
+            similarity_cache,
+            usage_reporter,
+            action,
+            query.clone(),
+            &significant_params,
         )
         .await?;
+        if let Some(similar_query) = similarities
+            .first()
+            .filter(|similarity| read_policy.accepts(similarity))
+        {
+            log::info!(
+                "Found cached similar response with score: {} ({})",
+                similar_query.score,
+                similar_query.reason
+            );
 
-    let response_json: Value = response.json().await.map_err(|err| {
-        log::error!("Failed to parse API response: {}", err);
-        anyhow!("{}", err.to_string())
-    })?;
+            let cached = CachedAnswer {
+                score: similar_query.score,
+                text: similar_query.query.text.clone(),
+                created_at: similar_query.query.created_at,
+                stale: read_policy.is_stale(similar_query),
+            };
+            set_cache_hit_meta(&cached, false);
+
+            let reservation = cached.stale.then(|| {
+                usage_reporter
+                    .check_budget(action, context.client_name.as_deref())
+                    .inspect_err(|err| {
+                        log::warn!("Not revalidating stale cached response: {}", err)
+                    })
+                    .ok()
+            });
+            if let Some(Some(reservation)) = reservation {
+                log::info!("Revalidating stale cached response for {}", action);
+
+                let http_client = http_client.clone();
+                let similarity_cache = similarity_cache.clone();
+                let usage_reporter = usage_reporter.clone();
+                let stale_id = similar_query.query.id();
+                let query = query.clone();
+                let request_body = request_body.clone();
+                let action = action.to_string();
+                let context = context.clone();
+                let pricing = pricing.clone();
+                // Traced as part of the tool call that found the stale answer
+                let cx = Context::current();
+                tokio::spawn(
+                    async move {
+                        let _reservation = reservation;
+                        let started = Instant::now();
+                        let fresh_id = query.id();
+                        match fetch_and_store(
+                            http_client,
+                            similarity_cache.clone(),
+                            &usage_reporter,
+                            &action,
+                            request_body,
+                            Some(query),
+                        )
+                        .await
+                        {
+                            Ok(response) => {
+                                // The fresh answer supersedes a fuzzy hit stored under another question
+                                if stale_id != fresh_id {
+                                    let _ =
+                                        similarity_cache.delete(&CacheSelector::Id(stale_id)).await;
+                                }
+                                report_usage(
+                                    &usage_reporter,
+                                    &pricing,
+                                    &action,
+                                    &context,
+                                    started,
+                                    &response,
+                                )
+                                .await;
+                            }
+                            Err(err) => {
+                                log::error!("Failed to revalidate cached response: {}", err)
+                            }
+                        }
+                    }
+                    .with_context(cx),
+                );
+            }
 
-    // Store the result in the similarity cache
-    let mut cached_query = query.clone();
-    cached_query.results = response_json.clone();
-    let _ = similarity_cache.store(cached_query).await;
+            let mut response = PerplexityResponse {
+                body: similar_query.query.results.clone(),
+                cached: Some(cached),
+                coalesced: false,
+                http_attempts: 0,
+                cost: None,
+            };
+            response.cost = report_usage(
+                usage_reporter,
+                pricing,
+                action,
+                &context,
+                started,
+                &response,
+            )
+            .await;
+
+            return Ok(response);
+        }
+    } else {
+        log::info!("Skipping similarity cache lookup for {}", action);
+    }
 
-    Ok(response_json)
+    // Only new calls count against a budget, cached answers remain available
+    let _reservation = usage_reporter
+        .check_budget(action, context.client_name.as_deref())
+        .map_err(|err| match err.downcast::<BudgetExceeded>() {
+            Ok(exceeded) => ToolError(exceeded.0).into(),
+            Err(err) => err,
+        })?;
+
+    let store = cache_policy::should_store(cache_policy, cache_directive);
+    let mut response = fetch_and_store(
+        http_client.clone(),
+        similarity_cache.clone(),
+        usage_reporter,
+        action,
+        request_body,
+        store.then_some(query),
+    )
+    .await?;
+
+    if response.coalesced {
+        log::info!("Shared an in-flight response for {}", action);
+    }
+    tool_call::set_meta(
+        "cache",
+        json!({ "hit": false, "coalesced": response.coalesced }),
+    );
+
+    response.cost = report_usage(
+        usage_reporter,
+        pricing,
+        action,
+        &context,
+        started,
+        &response,
+    )
+    .await;
+
+    Ok(response)
 }
 
 pub struct SearchTool {
     http_client: Arc<dyn HttpClient>,
     usage_reporter: Arc<dyn UsageReporter>,
     similarity_cache: Arc<dyn SimilarityCache>,
+    cache_policy: CachePolicy,
+    offline: bool,
+    pricing: Arc<PricingTable>,
+    show_cost: bool,
 }
 
 impl SearchTool {
+    pub const DEFAULT_CACHE_POLICY: CachePolicy = CachePolicy::Fuzzy {
+        threshold: DEFAULT_THRESHOLD,
+    };
+
     pub fn new(
         http_client: Arc<dyn HttpClient>,
         usage_reporter: Option<Arc<dyn UsageReporter>>,
@@ -131,8 +746,32 @@ impl SearchTool {
             usage_reporter: usage_reporter.unwrap_or_else(|| Arc::new(NoopUsageReporter)),
             similarity_cache: similarity_cache
                 .unwrap_or_else(|| Arc::new(PassthroughSimilarityCache)),
+            cache_policy: Self::DEFAULT_CACHE_POLICY,
+            offline: false,
+            pricing: Arc::new(PricingTable::default()),
+            show_cost: false,
         }
     }
+
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn with_pricing(mut self, pricing: Arc<PricingTable>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    pub fn with_cost_in_output(mut self, show_cost: bool) -> Self {
+        self.show_cost = show_cost;
+        self
+    }
 }
 
 #[async_trait]
@@ -141,6 +780,8 @@ impl ToolExecutor for SearchTool {
         log::debug!("Executing SearchTool");
         let args = arguments.ok_or_else(|| anyhow!("Missing arguments"))?;
 
+        let cache_directive = CacheDirective::from_arguments(&args)?;
+
         let query = args
             .get("query")
             .and_then(|v| v.as_str())
@@ -169,37 +810,30 @@ impl ToolExecutor for SearchTool {
 
         let messages = json!([{"role": "user", "content": prompt}]);
 
-        let response_body = call_perplexity_api(
+        let response = call_perplexity_api(
             &self.http_client,
             &self.similarity_cache,
-            "sonar-reasoning-pro",
-            messages,
-            search_recency_filter,
+            &self.usage_reporter,
+            PerplexityRequest {
+                action: "search",
+                model: "sonar-reasoning-pro",
+                messages,
+                cache_text: cache_text(&[query]),
+                search_recency_filter,
+                cache_params: json!({ "detail_level": detail_level }),
+                cache_policy: self.cache_policy,
+                cache_directive,
+                offline: self.offline,
+                pricing: &self.pricing,
+            },
         )
-        .await?;
-
-        // Report usage if available
-        if let (Some(usage), Some(model)) = (
-            response_body.get("usage"),
-            response_body.get("model").and_then(|m| m.as_str()),
-        ) {
-            if let (Some(completion_tokens), Some(prompt_tokens), Some(total_tokens)) = (
-                usage.get("completion_tokens").and_then(|t| t.as_u64()),
-                usage.get("prompt_tokens").and_then(|t| t.as_u64()),
-                usage.get("total_tokens").and_then(|t| t.as_u64()),
-            ) {
-                let _ = self.usage_reporter.report(UsageReport {
-                    model: model.to_string(),
-                    usage: Usage {
-                        completion_tokens,
-                        prompt_tokens,
-                        total_tokens,
-                    },
-                });
-            }
-        }
+        .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => return tool_call::error_result(err),
+        };
 
-        let content = format_response_with_references(&response_body)?;
+        let content = format_response(&response, self.show_cost)?;
 
         Ok(vec![ToolContent::Text { text: content }])
     }
@@ -227,7 +861,8 @@ impl ToolExecutor for SearchTool {
                         "type": "string",
                         "description": "Optional: Filter for search results recency (month, week, day, hour)",
                         "enum": ["month", "week", "day", "hour"]
-                    }
+                    },
+                    "cache": CacheDirective::input_schema()
                 },
                 "required": ["query"]
             }),
@@ -239,9 +874,17 @@ pub struct GetDocumentationTool {
     http_client: Arc<dyn HttpClient>,
     usage_reporter: Arc<dyn UsageReporter>,
     similarity_cache: Arc<dyn SimilarityCache>,
+    cache_policy: CachePolicy,
+    offline: bool,
+    pricing: Arc<PricingTable>,
+    show_cost: bool,
 }
 
 impl GetDocumentationTool {
+    pub const DEFAULT_CACHE_POLICY: CachePolicy = CachePolicy::Fuzzy {
+        threshold: DEFAULT_THRESHOLD,
+    };
+
     pub fn new(
         http_client: Arc<dyn HttpClient>,
         usage_reporter: Option<Arc<dyn UsageReporter>>,
@@ -252,8 +895,32 @@ impl GetDocumentationTool {
             usage_reporter: usage_reporter.unwrap_or_else(|| Arc::new(NoopUsageReporter)),
             similarity_cache: similarity_cache
                 .unwrap_or_else(|| Arc::new(PassthroughSimilarityCache)),
+            cache_policy: Self::DEFAULT_CACHE_POLICY,
+            offline: false,
+            pricing: Arc::new(PricingTable::default()),
+            show_cost: false,
         }
     }
+
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn with_pricing(mut self, pricing: Arc<PricingTable>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    pub fn with_cost_in_output(mut self, show_cost: bool) -> Self {
+        self.show_cost = show_cost;
+        self
+    }
 }
 
 #[async_trait]
@@ -262,6 +929,8 @@ impl ToolExecutor for GetDocumentationTool {
         log::debug!("Executing GetDocumentationTool");
         let args = arguments.ok_or_else(|| anyhow!("Missing arguments"))?;
 
+        let cache_directive = CacheDirective::from_arguments(&args)?;
+
         let query = args
             .get("query")
             .and_then(|v| v.as_str())
@@ -290,37 +959,30 @@ impl ToolExecutor for GetDocumentationTool {
 
         let messages = json!([{"role": "user", "content": prompt}]);
 
-        let response_body = call_perplexity_api(
+        let response = call_perplexity_api(
             &self.http_client,
             &self.similarity_cache,
-            "sonar-reasoning-pro",
-            messages,
-            None,
+            &self.usage_reporter,
+            PerplexityRequest {
+                action: "get_documentation",
+                model: "sonar-reasoning-pro",
+                messages,
+                cache_text: cache_text(&[query, context]),
+                search_recency_filter: None,
+                cache_params: json!({}),
+                cache_policy: self.cache_policy,
+                cache_directive,
+                offline: self.offline,
+                pricing: &self.pricing,
+            },
         )
-        .await?;
-
-        // Report usage if available
-        if let (Some(usage), Some(model)) = (
-            response_body.get("usage"),
-            response_body.get("model").and_then(|m| m.as_str()),
-        ) {
-            if let (Some(completion_tokens), Some(prompt_tokens), Some(total_tokens)) = (
-                usage.get("completion_tokens").and_then(|t| t.as_u64()),
-                usage.get("prompt_tokens").and_then(|t| t.as_u64()),
-                usage.get("total_tokens").and_then(|t| t.as_u64()),
-            ) {
-                let _ = self.usage_reporter.report(UsageReport {
-                    model: model.to_string(),
-                    usage: Usage {
-                        completion_tokens,
-                        prompt_tokens,
-                        total_tokens,
-                    },
-                });
-            }
-        }
+        .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => return tool_call::error_result(err),
+        };
 
-        let content = format_response_with_references(&response_body)?;
+        let content = format_response(&response, self.show_cost)?;
 
         Ok(vec![ToolContent::Text { text: content }])
     }
@@ -342,7 +1004,8 @@ impl ToolExecutor for GetDocumentationTool {
                     "context": {
                         "type": "string",
                         "description": "Additional context or specific aspects to focus on"
-                    }
+                    },
+                    "cache": CacheDirective::input_schema()
                 },
                 "required": ["query"]
             }),
@@ -354,9 +1017,17 @@ pub struct FindApisTool {
     http_client: Arc<dyn HttpClient>,
     usage_reporter: Arc<dyn UsageReporter>,
     similarity_cache: Arc<dyn SimilarityCache>,
+    cache_policy: CachePolicy,
+    offline: bool,
+    pricing: Arc<PricingTable>,
+    show_cost: bool,
 }
 
 impl FindApisTool {
+    pub const DEFAULT_CACHE_POLICY: CachePolicy = CachePolicy::Fuzzy {
+        threshold: DEFAULT_THRESHOLD,
+    };
+
     pub fn new(
         http_client: Arc<dyn HttpClient>,
         usage_reporter: Option<Arc<dyn UsageReporter>>,
@@ -367,8 +1038,32 @@ impl FindApisTool {
             usage_reporter: usage_reporter.unwrap_or_else(|| Arc::new(NoopUsageReporter)),
             similarity_cache: similarity_cache
                 .unwrap_or_else(|| Arc::new(PassthroughSimilarityCache)),
+            cache_policy: Self::DEFAULT_CACHE_POLICY,
+            offline: false,
+            pricing: Arc::new(PricingTable::default()),
+            show_cost: false,
         }
     }
+
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn with_pricing(mut self, pricing: Arc<PricingTable>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    pub fn with_cost_in_output(mut self, show_cost: bool) -> Self {
+        self.show_cost = show_cost;
+        self
+    }
 }
 
 #[async_trait]
@@ -377,6 +1072,8 @@ impl ToolExecutor for FindApisTool {
         log::debug!("Executing FindApisTool");
         let args = arguments.ok_or_else(|| anyhow!("Missing arguments"))?;
 
+        let cache_directive = CacheDirective::from_arguments(&args)?;
+
         let requirement = args
             .get("requirement")
             .and_then(|v| v.as_str())
@@ -409,37 +1106,30 @@ impl ToolExecutor for FindApisTool {
 
         let messages = json!([{"role": "user", "content": prompt}]);
 
-        let response_body = call_perplexity_api(
+        let response = call_perplexity_api(
             &self.http_client,
             &self.similarity_cache,
-            "sonar-reasoning-pro",
-            messages,
-            None,
+            &self.usage_reporter,
+            PerplexityRequest {
+                action: "find_apis",
+                model: "sonar-reasoning-pro",
+                messages,
+                cache_text: cache_text(&[requirement, context]),
+                search_recency_filter: None,
+                cache_params: json!({}),
+                cache_policy: self.cache_policy,
+                cache_directive,
+                offline: self.offline,
+                pricing: &self.pricing,
+            },
         )
-        .await?;
-
-        // Report usage if available
-        if let (Some(usage), Some(model)) = (
-            response_body.get("usage"),
-            response_body.get("model").and_then(|m| m.as_str()),
-        ) {
-            if let (Some(completion_tokens), Some(prompt_tokens), Some(total_tokens)) = (
-                usage.get("completion_tokens").and_then(|t| t.as_u64()),
-                usage.get("prompt_tokens").and_then(|t| t.as_u64()),
-                usage.get("total_tokens").and_then(|t| t.as_u64()),
-            ) {
-                let _ = self.usage_reporter.report(UsageReport {
-                    model: model.to_string(),
-                    usage: Usage {
-                        completion_tokens,
-                        prompt_tokens,
-                        total_tokens,
-                    },
-                });
-            }
-        }
+        .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => return tool_call::error_result(err),
+        };
 
-        let content = format_response_with_references(&response_body)?;
+        let content = format_response(&response, self.show_cost)?;
 
         Ok(vec![ToolContent::Text { text: content }])
     }
@@ -460,7 +1150,8 @@ impl ToolExecutor for FindApisTool {
                     "context": {
                         "type": "string",
                         "description": "Additional context about the project or specific needs"
-                    }
+                    },
+                    "cache": CacheDirective::input_schema()
                 },
                 "required": ["requirement"]
             }),
@@ -472,9 +1163,17 @@ pub struct CheckDeprecatedCodeTool {
     http_client: Arc<dyn HttpClient>,
     usage_reporter: Arc<dyn UsageReporter>,
     similarity_cache: Arc<dyn SimilarityCache>,
+    cache_policy: CachePolicy,
+    offline: bool,
+    pricing: Arc<PricingTable>,
+    show_cost: bool,
 }
 
 impl CheckDeprecatedCodeTool {
+    // Code a few tokens apart can call different APIs, so only the same code
+    // gets the same answer
+    pub const DEFAULT_CACHE_POLICY: CachePolicy = CachePolicy::ExactOnly;
+
     pub fn new(
         http_client: Arc<dyn HttpClient>,
         usage_reporter: Option<Arc<dyn UsageReporter>>,
@@ -485,8 +1184,32 @@ impl CheckDeprecatedCodeTool {
             usage_reporter: usage_reporter.unwrap_or_else(|| Arc::new(NoopUsageReporter)),
             similarity_cache: similarity_cache
                 .unwrap_or_else(|| Arc::new(PassthroughSimilarityCache)),
+            cache_policy: Self::DEFAULT_CACHE_POLICY,
+            offline: false,
+            pricing: Arc::new(PricingTable::default()),
+            show_cost: false,
         }
     }
+
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn with_pricing(mut self, pricing: Arc<PricingTable>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    pub fn with_cost_in_output(mut self, show_cost: bool) -> Self {
+        self.show_cost = show_cost;
+        self
+    }
 }
 
 #[async_trait]
@@ -495,6 +1218,8 @@ impl ToolExecutor for CheckDeprecatedCodeTool {
         log::debug!("Executing CheckDeprecatedCodeTool");
         let args = arguments.ok_or_else(|| anyhow!("Missing arguments"))?;
 
+        let cache_directive = CacheDirective::from_arguments(&args)?;
+
         let code = args
             .get("code")
             .and_then(|v| v.as_str())
@@ -532,37 +1257,30 @@ impl ToolExecutor for CheckDeprecatedCodeTool {
 
         let messages = json!([{"role": "user", "content": prompt}]);
 
-        let response_body = call_perplexity_api(
+        let response = call_perplexity_api(
             &self.http_client,
             &self.similarity_cache,
-            "sonar-reasoning-pro",
-            messages,
-            None,
+            &self.usage_reporter,
+            PerplexityRequest {
+                action: "check_deprecated_code",
+                model: "sonar-reasoning-pro",
+                messages,
+                cache_text: cache_text(&[code]),
+                search_recency_filter: None,
+                cache_params: json!({ "technology": technology }),
+                cache_policy: self.cache_policy,
+                cache_directive,
+                offline: self.offline,
+                pricing: &self.pricing,
+            },
         )
-        .await?;
-
-        // Report usage if available
-        if let (Some(usage), Some(model)) = (
-            response_body.get("usage"),
-            response_body.get("model").and_then(|m| m.as_str()),
-        ) {
-            if let (Some(completion_tokens), Some(prompt_tokens), Some(total_tokens)) = (
-                usage.get("completion_tokens").and_then(|t| t.as_u64()),
-                usage.get("prompt_tokens").and_then(|t| t.as_u64()),
-                usage.get("total_tokens").and_then(|t| t.as_u64()),
-            ) {
-                let _ = self.usage_reporter.report(UsageReport {
-                    model: model.to_string(),
-                    usage: Usage {
-                        completion_tokens,
-                        prompt_tokens,
-                        total_tokens,
-                    },
-                });
-            }
-        }
+        .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => return tool_call::error_result(err),
+        };
 
-        let content = format_response_with_references(&response_body)?;
+        let content = format_response(&response, self.show_cost)?;
 
         Ok(vec![ToolContent::Text { text: content }])
     }
@@ -583,10 +1301,160 @@ impl ToolExecutor for CheckDeprecatedCodeTool {
                     "technology": {
                         "type": "string",
                         "description": "The technology or framework context (e.g., 'React', 'Node.js')"
-                    }
+                    },
+                    "cache": CacheDirective::input_schema()
                 },
                 "required": ["code"]
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use http_client::{AsyncBody, Response};
+    use similarity_cache::{InMemorySimilarityCache, cosine_similarity};
+
+    use super::*;
+    use crate::cache_policy::DEFAULT_THRESHOLD;
+
+    // Answers with each of `statuses` in turn, then with success
+    struct FakePerplexity {
+        statuses: Mutex<Vec<u16>>,
+        requests: AtomicU32,
+    }
+
+    impl FakePerplexity {
+        fn new(statuses: &[u16]) -> Arc<Self> {
+            Arc::new(Self {
+                statuses: Mutex::new(statuses.iter().rev().copied().collect()),
+                requests: AtomicU32::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for FakePerplexity {
+        async fn send(&self, _request: Request<AsyncBody>) -> Result<Response<AsyncBody>> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let status = self
+                .statuses
+                .lock()
+                .map_err(|_| anyhow!("Fake lock poisoned"))?
+                .pop()
+                .unwrap_or(200);
+            let body = if status == 200 {
+                json!({ "choices": [{ "message": { "content": "42" } }] })
+            } else {
+                json!({ "error": { "message": "Something went wrong" } })
+            };
+
+            Ok(Response::builder()
+                .status(status)
+                .body(AsyncBody::from(body.to_string()))?)
+        }
+    }
+
+    async fn fetch(
+        http_client: Arc<FakePerplexity>,
+        cache: &Arc<InMemorySimilarityCache>,
+        question: &str,
+    ) -> Result<PerplexityResponse> {
+        // Safety: no test reads the environment while another writes it
+        unsafe { env::set_var("PERPLEXITY_API_KEY", "test") };
+        let text = cache_text(&[question]);
+        fetch_and_store(
+            http_client,
+            cache.clone(),
+            &(Arc::new(NoopUsageReporter) as Arc<dyn UsageReporter>),
+            "search",
+            json!({ "model": "sonar", "messages": question }),
+            Some(CacheQuery {
+                action: "search".into(),
+                embedding: embed(&text),
+                text,
+                params: None,
+                results: Value::Null,
+                created_at: Utc::now(),
+            }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_and_never_cached() -> Result<()> {
+        let cache = Arc::new(InMemorySimilarityCache::new());
+        let perplexity = FakePerplexity::new(&[500, 500, 500]);
+
+        let err = fetch(perplexity.clone(), &cache, "failing question")
+            .await
+            .err()
+            .map(|err| err.to_string());
+        assert_eq!(
+            err.as_deref(),
+            Some("Perplexity API responded with 500: Something went wrong")
+        );
+        assert_eq!(perplexity.requests.load(Ordering::SeqCst), MAX_ATTEMPTS);
+        assert!(cache.entries().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() -> Result<()> {
+        let cache = Arc::new(InMemorySimilarityCache::new());
+        let perplexity = FakePerplexity::new(&[401]);
+
+        assert!(
+            fetch(perplexity.clone(), &cache, "unauthorized question")
+                .await
+                .is_err()
+        );
+        assert_eq!(perplexity.requests.load(Ordering::SeqCst), 1);
+        assert!(cache.entries().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_after_a_retry_are_cached() -> Result<()> {
+        let cache = Arc::new(InMemorySimilarityCache::new());
+        let perplexity = FakePerplexity::new(&[503]);
+
+        let response = fetch(perplexity.clone(), &cache, "flaky question").await?;
+        assert_eq!(response.http_attempts, 2);
+        assert_eq!(cache.entries().await?.len(), 1);
+        Ok(())
+    }
+
+    fn score(a: &[&str], b: &[&str]) -> f32 {
+        cosine_similarity(&embed(&cache_text(a)), &embed(&cache_text(b)))
+    }
+
+    #[test]
+    fn unrelated_requests_score_below_the_default_threshold() {
+        for (a, b) in [
+            (&["tokio"][..], &["serde"][..]),
+            (&["React hooks"], &["Vue composition API"]),
+            (
+                &["How do I read a file line by line in Rust?"],
+                &["What changed in Python 3.13?"],
+            ),
+            (&["tokio", "select"], &["tokio", "spawn_blocking"]),
+        ] {
+            let score = score(a, b);
+            assert!(score < DEFAULT_THRESHOLD, "{:?} vs {:?}: {}", a, b, score);
+        }
+    }
+
+    #[test]
+    fn case_and_spacing_do_not_matter() {
+        assert!(score(&["Tokio  select!"], &["tokio select!"]) >= DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn empty_arguments_are_left_out() {
+        assert_eq!(cache_text(&["tokio", " ", ""]), "tokio");
+        assert_eq!(cache_text(&[" tokio ", "select"]), "tokio\nselect");
+    }
+}
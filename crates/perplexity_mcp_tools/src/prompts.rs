@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use context_server::{Prompt, PromptArgument, PromptExecutor};
+use indoc::formatdoc;
+
+const ECOSYSTEMS: &[&str] = &[
+    "c#",
+    "c++",
+    "dart",
+    "elixir",
+    "go",
+    "java",
+    "javascript",
+    "kotlin",
+    "php",
+    "python",
+    "ruby",
+    "rust",
+    "scala",
+    "swift",
+    "typescript",
+];
+
+// At most this many completions are returned, as MCP allows
+const MAX_COMPLETIONS: usize = 100;
+
+struct ResearchArgument {
+    name: &'static str,
+    description: &'static str,
+    required: bool,
+    // Offered as completions, filtered by what has been typed so far
+    suggestions: &'static [&'static str],
+}
+
+type Arguments = HashMap<String, String>;
+
+// A research workflow, expanded into instructions on how to combine the tools
+pub struct ResearchPrompt {
+    name: &'static str,
+    description: &'static str,
+    arguments: &'static [ResearchArgument],
+    render: fn(&Arguments) -> String,
+}
+
+impl ResearchPrompt {
+    // Completions for `argument`, given the value typed so far
+    pub fn complete(&self, argument: &str, value: &str) -> Vec<String> {
+        let value = value.to_lowercase();
+        self.arguments
+            .iter()
+            .filter(|spec| spec.name == argument)
+            .flat_map(|spec| spec.suggestions)
+            .filter(|suggestion| suggestion.starts_with(&value))
+            .take(MAX_COMPLETIONS)
+            .map(|suggestion| suggestion.to_string())
+            .collect()
+    }
+}
+
+#[async_trait]
+impl PromptExecutor for ResearchPrompt {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn compute(&self, arguments: Option<HashMap<String, String>>) -> Result<String> {
+        let mut arguments = arguments.unwrap_or_default();
+        arguments.retain(|_, value| !value.trim().is_empty());
+
+        if let Some(missing) = self
+            .arguments
+            .iter()
+            .find(|spec| spec.required && !arguments.contains_key(spec.name))
+        {
+            return Err(anyhow!(
+                "The {} prompt is missing its {} argument",
+                self.name,
+                missing.name
+            ));
+        }
+
+        Ok((self.render)(&arguments))
+    }
+
+    fn to_prompt(&self) -> Prompt {
+        Prompt {
+            name: self.name.into(),
+            description: Some(self.description.into()),
+            arguments: Some(
+                self.arguments
+                    .iter()
+                    .map(|spec| PromptArgument {
+                        name: spec.name.into(),
+                        description: Some(spec.description.into()),
+                        required: Some(spec.required),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn argument<'a>(arguments: &'a Arguments, name: &str) -> &'a str {
+    arguments.get(name).map(String::as_str).unwrap_or_default()
+}
+
+// A sentence naming an optional argument, or nothing when it was not given
+fn optional(arguments: &Arguments, name: &str, sentence: &str) -> String {
+    arguments
+        .get(name)
+        .map(|value| format!("\n{}", sentence.replace("{}", value)))
+        .unwrap_or_default()
+}
+
+fn evaluate_library(arguments: &Arguments) -> String {
+    let library = argument(arguments, "library");
+    formatdoc! {"
+        Evaluate whether {library} is a good choice for this project.{ecosystem}{use_case}
+
+        1. Call `get_documentation` for {library} to learn its scope, its API and how it is meant to be used.
+        2. Call `search` with `search_recency_filter` set to `month` for its latest releases, maintenance activity, open issues and known security problems.
+        3. Call `find_apis` with the requirement it should meet, to find the alternatives worth weighing against it.
+        4. Before adopting any example from the documentation, call `check_deprecated_code` on it.
+
+        Finish with a recommendation: adopt, adopt with caveats, or avoid. Back it with a short table comparing {library} to the strongest alternative on maturity, maintenance, API ergonomics and licensing, citing the sources behind each point.",
+        ecosystem = optional(arguments, "ecosystem", "It would be used from {}."),
+        use_case = optional(arguments, "use_case", "It is needed for: {}."),
+    }
+}
+
+fn plan_migration(arguments: &Arguments) -> String {
+    let library = argument(arguments, "library");
+    let from = argument(arguments, "from_version");
+    let to = argument(arguments, "to_version");
+    formatdoc! {"
+        Plan the migration of this project from {library} {from} to {library} {to}.{ecosystem}
+
+        1. Call `get_documentation` for the {library} {to} migration guide and the changelogs of every release in between.
+        2. Call `search` for breaking changes, known regressions and other people's experience of upgrading from {from} to {to}.
+        3. Call `check_deprecated_code` with `technology` set to \"{library} {to}\" on each part of the codebase that uses {library}, to find what no longer works.
+        4. For each replacement the new version needs, call `find_apis` if the documentation does not already name one.
+
+        Finish with an ordered plan, each step small enough to land and test on its own, listing the code it touches, the change to make and how to verify it. Call out the risky steps and anything that has no drop-in replacement.",
+        ecosystem = optional(arguments, "ecosystem", "The project is written in {}."),
+    }
+}
+
+fn research_error(arguments: &Arguments) -> String {
+    let error = argument(arguments, "error_message");
+    formatdoc! {"
+        Find out what causes this error and how to fix it:
+
+        ```
+        {error}
+        ```{language}{context}
+
+        1. Call `search` with the distinctive part of the error message, leaving out paths, line numbers and identifiers from this project. Set `search_recency_filter` to `month` first, and widen it if nothing relevant turns up.
+        2. Call `get_documentation` for the API or tool that raises it, to understand what it expects.
+        3. If the error comes from code in this project, call `check_deprecated_code` on that code, since removed and changed APIs are a common cause.
+
+        Finish with the likely causes, most probable first, each with the evidence for it and a concrete fix. Say what to check to tell the causes apart.",
+        language = optional(arguments, "language", "It happens in a {} project."),
+        context = optional(arguments, "context", "What was going on when it happened: {}."),
+    }
+}
+
+fn compare_apis(arguments: &Arguments) -> String {
+    let first = argument(arguments, "first");
+    let second = argument(arguments, "second");
+    formatdoc! {"
+        Compare {first} with {second}.{use_case}{ecosystem}
+
+        1. Call `get_documentation` for {first} and for {second}, to compare their features, their API design and their limits.
+        2. Call `search` for benchmarks, production experience and the trade-offs people report between the two.
+        3. Call `find_apis` with the use case, in case a third option fits it better than either.
+        4. Call `check_deprecated_code` on the examples you rely on, so that the comparison is based on current APIs.
+
+        Finish with a table comparing them on capabilities, performance, ergonomics, maturity and cost, followed by a recommendation for this use case and when the other would be the better choice.",
+        use_case = optional(arguments, "use_case", "They would be used for: {}."),
+        ecosystem = optional(arguments, "ecosystem", "The project is written in {}."),
+    }
+}
+
+pub fn research_prompts() -> Vec<ResearchPrompt> {
+    vec![
+        ResearchPrompt {
+            name: "evaluate_library",
+            description: "Evaluate a library before adopting it: its maintenance, API and alternatives",
+            arguments: &[
+                ResearchArgument {
+                    name: "library",
+                    description: "The library to evaluate",
+                    required: true,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "ecosystem",
+                    description: "The language or platform it would be used from",
+                    required: false,
+                    suggestions: ECOSYSTEMS,
+                },
+                ResearchArgument {
+                    name: "use_case",
+                    description: "What it is needed for",
+                    required: false,
+                    suggestions: &[],
+                },
+            ],
+            render: evaluate_library,
+        },
+        ResearchPrompt {
+            name: "plan_migration",
+            description: "Plan an upgrade of a library or framework from one version to another",
+            arguments: &[
+                ResearchArgument {
+                    name: "library",
+                    description: "The library or framework to upgrade",
+                    required: true,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "from_version",
+                    description: "The version in use",
+                    required: true,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "to_version",
+                    description: "The version to upgrade to",
+                    required: true,
+                    suggestions: &["latest"],
+                },
+                ResearchArgument {
+                    name: "ecosystem",
+                    description: "The language or platform of the project",
+                    required: false,
+                    suggestions: ECOSYSTEMS,
+                },
+            ],
+            render: plan_migration,
+        },
+        ResearchPrompt {
+            name: "research_error",
+            description: "Research the causes of an error message and how to fix it",
+            arguments: &[
+                ResearchArgument {
+                    name: "error_message",
+                    description: "The error message, as reported",
+                    required: true,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "language",
+                    description: "The language or platform of the project",
+                    required: false,
+                    suggestions: ECOSYSTEMS,
+                },
+                ResearchArgument {
+                    name: "context",
+                    description: "What was being done when the error happened",
+                    required: false,
+                    suggestions: &[],
+                },
+            ],
+            render: research_error,
+        },
+        ResearchPrompt {
+            name: "compare_apis",
+            description: "Compare two APIs, libraries or services for a use case",
+            arguments: &[
+                ResearchArgument {
+                    name: "first",
+                    description: "The first API, library or service",
+                    required: true,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "second",
+                    description: "The second API, library or service",
+                    required: true,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "use_case",
+                    description: "What they would be used for",
+                    required: false,
+                    suggestions: &[],
+                },
+                ResearchArgument {
+                    name: "ecosystem",
+                    description: "The language or platform of the project",
+                    required: false,
+                    suggestions: ECOSYSTEMS,
+                },
+            ],
+            render: compare_apis,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(name: &str) -> Result<ResearchPrompt> {
+        research_prompts()
+            .into_iter()
+            .find(|prompt| prompt.name == name)
+            .ok_or_else(|| anyhow!("No {} prompt", name))
+    }
+
+    fn arguments(pairs: &[(&str, &str)]) -> Option<Arguments> {
+        Some(
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn missing_or_blank_required_arguments_are_rejected() -> Result<()> {
+        let prompt = prompt("plan_migration")?;
+
+        let missing = prompt
+            .compute(arguments(&[("library", "tokio"), ("to_version", "1.42")]))
+            .await;
+        assert_eq!(
+            missing.map_err(|err| err.to_string()),
+            Err("The plan_migration prompt is missing its from_version argument".to_string())
+        );
+
+        let blank = prompt
+            .compute(arguments(&[
+                ("library", "tokio"),
+                ("from_version", "  "),
+                ("to_version", "1.42"),
+            ]))
+            .await;
+        assert!(blank.is_err());
+        assert!(prompt.compute(None).await.is_err());
+
+        let plan = prompt
+            .compute(arguments(&[
+                ("library", "tokio"),
+                ("from_version", "0.2"),
+                ("to_version", "1.42"),
+            ]))
+            .await?;
+        assert!(
+            plan.starts_with("Plan the migration of this project from tokio 0.2 to tokio 1.42.")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unset_optional_arguments_leave_no_sentence() -> Result<()> {
+        let prompt = prompt("evaluate_library")?;
+
+        let bare = prompt.compute(arguments(&[("library", "tokio")])).await?;
+        assert!(
+            bare.starts_with("Evaluate whether tokio is a good choice for this project.\n\n1.")
+        );
+        assert!(!bare.contains("{}"));
+
+        // Blank ones count as unset
+        let full = prompt
+            .compute(arguments(&[
+                ("library", "tokio"),
+                ("ecosystem", "rust"),
+                ("use_case", " "),
+            ]))
+            .await?;
+        assert!(
+            full.contains("a good choice for this project.\nIt would be used from rust.\n\n1.")
+        );
+        assert!(!full.contains("It is needed for"));
+        Ok(())
+    }
+
+    #[test]
+    fn completions_match_what_was_typed() -> Result<()> {
+        let prompt = prompt("evaluate_library")?;
+
+        assert_eq!(
+            prompt.complete("ecosystem", "ja"),
+            vec!["java", "javascript"]
+        );
+        assert_eq!(prompt.complete("ecosystem", "Ru"), vec!["ruby", "rust"]);
+        assert_eq!(prompt.complete("ecosystem", "").len(), ECOSYSTEMS.len());
+        assert!(prompt.complete("ecosystem", "cobol").is_empty());
+        assert!(prompt.complete("library", "to").is_empty());
+        assert!(prompt.complete("unknown", "").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn completions_are_capped() {
+        let suggestions = (0..MAX_COMPLETIONS + 50)
+            .map(|index| &*Box::leak(format!("crate-{}", index).into_boxed_str()))
+            .collect::<Vec<_>>();
+        let prompt = ResearchPrompt {
+            name: "many",
+            description: "",
+            arguments: Box::leak(Box::new([ResearchArgument {
+                name: "library",
+                description: "",
+                required: false,
+                suggestions: suggestions.leak(),
+            }])),
+            render: |_| String::new(),
+        };
+
+        let completions = prompt.complete("library", "crate-");
+        assert_eq!(completions.len(), MAX_COMPLETIONS);
+        assert_eq!(completions[0], "crate-0");
+    }
+}
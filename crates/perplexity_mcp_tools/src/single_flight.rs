@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+type Flight = Arc<OnceCell<Result<Value, String>>>;
+
+// Lets concurrent identical requests share a single in-flight call
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    flights: Mutex<HashMap<String, Flight>>,
+}
+
+impl SingleFlight {
+    // Returns the result and whether it was produced by another caller's call
+    pub async fn run<F, Fut>(&self, key: String, call: F) -> Result<(Value, bool)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Value>>,
+    {
+        let flight = {
+            let mut flights = self
+                .flights
+                .lock()
+                .map_err(|_| anyhow!("Single flight lock poisoned"))?;
+            flights.entry(key.clone()).or_default().clone()
+        };
+
+        let mut leader = false;
+        let result = flight
+            .get_or_init(|| {
+                leader = true;
+                async move { call().await.map_err(|err| err.to_string()) }
+            })
+            .await
+            .clone();
+
+        // Whoever finishes first retires the flight, so later requests start a new one
+        if let Ok(mut flights) = self.flights.lock()
+            && flights
+                .get(&key)
+                .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            flights.remove(&key);
+        }
+
+        result
+            .map(|value| (value, !leader))
+            .map_err(|err| anyhow!(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    async fn call(calls: &AtomicUsize, result: Result<Value>) -> Result<Value> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        result
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_requests_share_one_call() -> Result<()> {
+        let flights = SingleFlight::default();
+        let calls = AtomicUsize::new(0);
+
+        let (first, second, third) = tokio::join!(
+            flights.run("search".into(), || call(&calls, Ok(json!(1)))),
+            flights.run("search".into(), || call(&calls, Ok(json!(2)))),
+            flights.run("search".into(), || call(&calls, Ok(json!(3)))),
+        );
+        let results = [first?, second?, third?];
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|(value, _)| *value == json!(1)));
+        assert_eq!(
+            results.iter().filter(|(_, coalesced)| !coalesced).count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn different_requests_are_not_shared() -> Result<()> {
+        let flights = SingleFlight::default();
+        let calls = AtomicUsize::new(0);
+
+        let (first, second) = tokio::join!(
+            flights.run("search".into(), || call(&calls, Ok(json!(1)))),
+            flights.run("docs".into(), || call(&calls, Ok(json!(2)))),
+        );
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(first?, (json!(1), false));
+        assert_eq!(second?, (json!(2), false));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failures_are_shared_but_not_kept() -> Result<()> {
+        let flights = SingleFlight::default();
+        let calls = AtomicUsize::new(0);
+
+        let (first, second) = tokio::join!(
+            flights.run("search".into(), || call(&calls, Err(anyhow!("timed out")))),
+            flights.run("search".into(), || call(&calls, Ok(json!(2)))),
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(first.is_err_and(|err| err.to_string() == "timed out"));
+        assert!(second.is_err());
+
+        // The failed flight is retired, so the next request calls again
+        let retried = flights
+            .run("search".into(), || call(&calls, Ok(json!(3))))
+            .await?;
+        assert_eq!(retried, (json!(3), false));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use std::future::Future;
+
+use anyhow::Result;
+use opentelemetry::{
+    Context, KeyValue, global,
+    trace::{FutureExt, Status, TraceContextExt, Tracer},
+};
+
+pub(crate) const TRACER_NAME: &str = "perplexity-mcp";
+
+// Runs `future` in a child span of the current one, which is the tool call
+// span when tracing is enabled. Without a tracer provider installed, spans are
+// no-ops
+pub(crate) async fn in_span<T>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+
+    let result = future.with_context(cx.clone()).await;
+    if let Err(err) = &result {
+        cx.span().set_status(Status::error(err.to_string()));
+    }
+    cx.span().end();
+
+    result
+}
+
+pub(crate) fn set_attributes(attributes: impl IntoIterator<Item = KeyValue>) {
+    Context::current().span().set_attributes(attributes);
+}
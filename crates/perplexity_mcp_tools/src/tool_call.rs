@@ -0,0 +1,94 @@
+use std::{fmt, future::Future, sync::Mutex};
+
+use anyhow::Result;
+use context_server::ToolContent;
+use serde_json::{Map, Value};
+
+// What the server knows about the JSON-RPC request behind a tool call
+#[derive(Clone, Debug, Default)]
+pub struct ToolCallContext {
+    pub request_id: Option<String>,
+    // The client name sent in `initialize`
+    pub client_name: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ToolCallMeta {
+    pub meta: Map<String, Value>,
+    pub is_error: bool,
+}
+
+struct ToolCall {
+    context: ToolCallContext,
+    meta: Mutex<ToolCallMeta>,
+}
+
+tokio::task_local! {
+    static TOOL_CALL: ToolCall;
+}
+
+// Runs `future` with `context` available to the tools, collecting the `_meta`
+// entries attached to the tool result while it runs
+pub async fn with_tool_call<F: Future>(
+    context: ToolCallContext,
+    future: F,
+) -> (F::Output, ToolCallMeta) {
+    let tool_call = ToolCall {
+        context,
+        meta: Mutex::new(ToolCallMeta::default()),
+    };
+
+    TOOL_CALL
+        .scope(tool_call, async {
+            let output = future.await;
+            let meta = TOOL_CALL.with(|tool_call| {
+                tool_call
+                    .meta
+                    .lock()
+                    .map(|mut meta| std::mem::take(&mut *meta))
+                    .unwrap_or_default()
+            });
+            (output, meta)
+        })
+        .await
+}
+
+pub(crate) fn context() -> ToolCallContext {
+    TOOL_CALL
+        .try_with(|tool_call| tool_call.context.clone())
+        .unwrap_or_default()
+}
+
+fn update_meta(update: impl FnOnce(&mut ToolCallMeta)) {
+    let _ = TOOL_CALL.try_with(|tool_call| {
+        if let Ok(mut meta) = tool_call.meta.lock() {
+            update(&mut meta);
+        }
+    });
+}
+
+pub(crate) fn set_meta(key: &str, value: Value) {
+    update_meta(|meta| {
+        meta.meta.insert(key.to_string(), value);
+    });
+}
+
+// A failure the model should see and act on, reported as a tool result with
+// `isError` set rather than as a protocol error
+#[derive(Debug)]
+pub(crate) struct ToolError(pub String);
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ToolError {}
+
+pub(crate) fn error_result(err: anyhow::Error) -> Result<Vec<ToolContent>> {
+    let err = err.downcast::<ToolError>()?;
+    update_meta(|meta| meta.is_error = true);
+
+    Ok(vec![ToolContent::Text { text: err.0 }])
+}
@@ -0,0 +1,146 @@
+// Compares recall and latency of the HNSW index against the brute-force cache.
+//
+//     cargo run --release -p similarity_cache --example ann_benchmark -- [entries] [queries]
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use serde_json::Value;
+use similarity_cache::{
+    CacheQuery, HnswParams, HnswSimilarityCache, InMemorySimilarityCache, SimilarityCache,
+};
+
+const DIMENSIONS: usize = 256;
+const CLUSTERS: usize = 64;
+const TOP_K: usize = 10;
+
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn vector(&mut self) -> Vec<f32> {
+        (0..DIMENSIONS).map(|_| self.next() - 0.5).collect()
+    }
+}
+
+// Clustered vectors resemble embeddings of related questions better than uniform noise
+fn embedding(random: &mut Random, centroids: &[Vec<f32>]) -> Vec<f32> {
+    let centroid = &centroids[(random.next() * centroids.len() as f32) as usize % centroids.len()];
+    let noise = random.vector();
+    let vector = centroid
+        .iter()
+        .zip(noise)
+        .map(|(c, n)| c + n * 0.5)
+        .collect::<Vec<_>>();
+
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    vector.into_iter().map(|v| v / norm).collect()
+}
+
+fn query(index: usize, embedding: Vec<f32>) -> CacheQuery {
+    CacheQuery {
+        action: "benchmark".into(),
+        text: format!("query {}", index),
+        params: None,
+        embedding,
+        results: Value::Null,
+        created_at: Utc::now(),
+    }
+}
+
+async fn top_k(cache: &dyn SimilarityCache, query: CacheQuery) -> Result<(Vec<String>, Duration)> {
+    let start = Instant::now();
+    let similarities = cache.similarities(query, &[]).await?;
+    let elapsed = start.elapsed();
+
+    Ok((
+        similarities
+            .iter()
+            .take(TOP_K)
+            .map(|similarity| similarity.query.text.clone())
+            .collect(),
+        elapsed,
+    ))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let entries: usize = args
+        .first()
+        .map(|v| v.parse())
+        .transpose()?
+        .unwrap_or(20_000);
+    let queries: usize = args.get(1).map(|v| v.parse()).transpose()?.unwrap_or(200);
+
+    let mut random = Random(0x9e3779b97f4a7c15);
+    let centroids = (0..CLUSTERS).map(|_| random.vector()).collect::<Vec<_>>();
+
+    let brute_force = InMemorySimilarityCache::new();
+    let hnsw = HnswSimilarityCache::new(HnswParams::default());
+
+    let start = Instant::now();
+    for index in 0..entries {
+        brute_force
+            .store(query(index, embedding(&mut random, &centroids)))
+            .await?;
+    }
+    println!(
+        "brute force: inserted {} entries in {:?}",
+        entries,
+        start.elapsed()
+    );
+
+    let mut random = Random(0x9e3779b97f4a7c15);
+    let centroids = (0..CLUSTERS).map(|_| random.vector()).collect::<Vec<_>>();
+    let start = Instant::now();
+    for index in 0..entries {
+        hnsw.store(query(index, embedding(&mut random, &centroids)))
+            .await?;
+    }
+    println!(
+        "hnsw:        inserted {} entries in {:?}",
+        entries,
+        start.elapsed()
+    );
+
+    let mut brute_force_latency = Duration::ZERO;
+    let mut hnsw_latency = Duration::ZERO;
+    let mut found = 0;
+
+    for index in 0..queries {
+        let embedding = embedding(&mut random, &centroids);
+
+        let (expected, elapsed) =
+            top_k(&brute_force, query(entries + index, embedding.clone())).await?;
+        brute_force_latency += elapsed;
+
+        let (actual, elapsed) = top_k(&hnsw, query(entries + index, embedding)).await?;
+        hnsw_latency += elapsed;
+
+        found += actual.iter().filter(|text| expected.contains(text)).count();
+    }
+
+    println!(
+        "recall@{}: {:.3}",
+        TOP_K,
+        found as f64 / (queries * TOP_K) as f64
+    );
+    println!(
+        "mean latency: brute force {:?}, hnsw {:?}",
+        brute_force_latency / queries as u32,
+        hnsw_latency / queries as u32
+    );
+
+    Ok(())
+}
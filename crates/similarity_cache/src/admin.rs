@@ -0,0 +1,172 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::{CacheQuery, SimilarityCache, cosine_similarity, embed};
+
+#[derive(Clone, Debug)]
+pub enum CacheSelector {
+    All,
+    Id(String),
+    Action(String),
+    OlderThan(DateTime<Utc>),
+}
+
+impl CacheSelector {
+    pub fn older_than_days(days: u64) -> Result<Self> {
+        i64::try_from(days)
+            .ok()
+            .and_then(TimeDelta::try_days)
+            .and_then(|age| Utc::now().checked_sub_signed(age))
+            .map(CacheSelector::OlderThan)
+            .ok_or_else(|| anyhow!("{} days is too far back", days))
+    }
+
+    pub fn matches(&self, entry: &CacheQuery) -> bool {
+        match self {
+            CacheSelector::All => true,
+            CacheSelector::Id(id) => entry.id() == *id,
+            CacheSelector::Action(action) => entry.action == *action,
+            CacheSelector::OlderThan(time) => entry.created_at < *time,
+        }
+    }
+}
+
+// Ranks every cached entry by how close its text is to `text`, regardless of action or parameters
+pub async fn search(
+    cache: &dyn SimilarityCache,
+    text: &str,
+    limit: usize,
+) -> Result<Vec<(f32, CacheQuery)>> {
+    let embedding = embed(text);
+    let needle = text.to_lowercase();
+
+    let mut results = cache
+        .entries()
+        .await?
+        .into_iter()
+        .map(|entry| {
+            let score = if entry.text.to_lowercase().contains(&needle) {
+                1.0
+            } else {
+                cosine_similarity(&entry.embedding, &embedding)
+            };
+            (score, entry)
+        })
+        .collect::<Vec<_>>();
+
+    results.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    results.truncate(limit);
+
+    Ok(results)
+}
+
+pub async fn export_jsonl<W: Write + Send>(
+    cache: &dyn SimilarityCache,
+    mut writer: W,
+) -> Result<usize> {
+    let entries = cache.entries().await?;
+
+    for entry in &entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(entries.len())
+}
+
+// Writes the `limit` most recent entries without their embeddings, which
+// import recomputes, for when the export has to stay small
+pub async fn export_recent_jsonl<W: Write + Send>(
+    cache: &dyn SimilarityCache,
+    mut writer: W,
+    limit: usize,
+) -> Result<usize> {
+    let entries = cache.entries().await?;
+    let recent = &entries[entries.len().saturating_sub(limit)..];
+
+    for entry in recent {
+        let entry = CacheQuery {
+            embedding: Vec::new(),
+            ..entry.clone()
+        };
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(recent.len())
+}
+
+pub async fn import_jsonl<R: BufRead + Send>(
+    cache: &dyn SimilarityCache,
+    reader: R,
+) -> Result<usize> {
+    let mut imported = 0;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut entry: CacheQuery = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid cache entry on line {}", index + 1))?;
+        if entry.embedding.is_empty() {
+            entry.embedding = embed(&entry.text);
+        }
+
+        cache.store(entry).await?;
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::InMemorySimilarityCache;
+
+    #[test]
+    fn far_off_ages_are_an_error() {
+        assert!(CacheSelector::older_than_days(30).is_ok());
+        assert!(CacheSelector::older_than_days(u64::MAX).is_err());
+        assert!(CacheSelector::older_than_days(i64::MAX as u64).is_err());
+    }
+
+    #[tokio::test]
+    async fn recent_exports_leave_out_embeddings() -> Result<()> {
+        let cache = InMemorySimilarityCache::new();
+        for index in 0..5 {
+            let text = format!("question {}", index);
+            cache
+                .store(CacheQuery {
+                    action: "search".into(),
+                    embedding: embed(&text),
+                    text,
+                    params: None,
+                    results: json!({ "answer": index }),
+                    created_at: Utc::now(),
+                })
+                .await?;
+        }
+
+        let mut buffer = Vec::new();
+        assert_eq!(export_recent_jsonl(&cache, &mut buffer, 2).await?, 2);
+
+        let imported = InMemorySimilarityCache::new();
+        import_jsonl(&imported, buffer.as_slice()).await?;
+        let entries = imported.entries().await?;
+        assert_eq!(
+            entries.iter().map(|e| e.text.as_str()).collect::<Vec<_>>(),
+            ["question 3", "question 4"]
+        );
+        assert!(entries.iter().all(|e| e.embedding == embed(&e.text)));
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+const DIMENSIONS: usize = 256;
+
+// FNV-1a, so that embeddings stay stable across builds and can be persisted.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Embeds text as a normalised bag of hashed character trigrams.
+pub fn embed(text: &str) -> Vec<f32> {
+    let mut embedding = vec![0.0; DIMENSIONS];
+
+    let normalized = text
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let chars = normalized.chars().collect::<Vec<_>>();
+
+    for trigram in chars.windows(3) {
+        let trigram = trigram.iter().collect::<String>();
+        embedding[hash(trigram.as_bytes()) as usize % DIMENSIONS] += 1.0;
+    }
+
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+
+    embedding
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm_a = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
@@ -0,0 +1,274 @@
+use std::{env, fmt::Display, fs, path::PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::{
+    AeadCore, Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, OsRng, Payload},
+};
+
+const DEFAULT_KEY_ENV: &str = "PERPLEXITY_MCP_CACHE_KEY";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+// Marks an encrypted record, so plain text left from before encryption was
+// turned on can still be told apart and migrated. The id of the record follows,
+// then its nonce and ciphertext
+pub(crate) const ENCRYPTED_PREFIX: &str = "enc1:";
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    // Environment variable holding the base64 key, used when `key_file` is unset
+    pub key_env: String,
+    pub key_file: Option<PathBuf>,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            key_env: DEFAULT_KEY_ENV.into(),
+            key_file: None,
+        }
+    }
+}
+
+impl EncryptionConfig {
+    pub fn cipher(&self) -> Result<Cipher> {
+        let encoded = match &self.key_file {
+            Some(path) => {
+                warn_if_shared(path);
+                fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read key file {}", path.display()))?
+            }
+            None => env::var(&self.key_env).map_err(|_| {
+                anyhow!(
+                    "Cache encryption is enabled but {} is not set",
+                    self.key_env
+                )
+            })?,
+        };
+
+        Cipher::from_encoded_key(&encoded)
+    }
+}
+
+#[cfg(unix)]
+fn warn_if_shared(path: &PathBuf) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        log::warn!(
+            "Key file {} is readable by other users, consider `chmod 600`",
+            path.display()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_shared(_path: &PathBuf) {}
+
+// Authenticated encryption of cache records with XChaCha20-Poly1305, each
+// record carrying its own random nonce
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: XChaCha20Poly1305,
+    migrate_plaintext: bool,
+}
+
+impl Cipher {
+    pub fn from_encoded_key(encoded: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(encoded.trim())
+            .context("Encryption key is not valid base64")?;
+        if key.len() != KEY_LEN {
+            return Err(anyhow!(
+                "Encryption key must be {} bytes, got {}",
+                KEY_LEN,
+                key.len()
+            ));
+        }
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            migrate_plaintext: false,
+        })
+    }
+
+    // Lets plain text records be read so that they get encrypted, which only
+    // `perplexity-mcp cache encrypt` asks for
+    pub fn migrating_plaintext(mut self) -> Self {
+        self.migrate_plaintext = true;
+        self
+    }
+
+    pub fn accept_plaintext(&self, record: impl Display) -> Result<()> {
+        if self.migrate_plaintext {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "{} is not encrypted, run `perplexity-mcp cache encrypt` to encrypt it",
+            record
+        ))
+    }
+
+    pub fn generate_key() -> String {
+        STANDARD.encode(XChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    // The id is authenticated along with the record, so that a record moved
+    // to another entry no longer decrypts
+    pub fn encrypt(&self, id: &str, plaintext: &[u8]) -> Result<String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: id.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("Failed to encrypt cache record"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+
+        Ok(format!(
+            "{}{}:{}",
+            ENCRYPTED_PREFIX,
+            id,
+            STANDARD.encode(sealed)
+        ))
+    }
+
+    pub fn decrypt(&self, id: &str, record: &str) -> Result<Vec<u8>> {
+        let (record_id, sealed) = split(record)?;
+        if record_id != id {
+            return Err(anyhow!(
+                "Cache record of {} was found in place of {}",
+                record_id,
+                id
+            ));
+        }
+
+        let sealed = STANDARD
+            .decode(sealed.trim())
+            .context("Encrypted cache record is not valid base64")?;
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted cache record is truncated"));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: id.as_bytes(),
+        };
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("Failed to decrypt cache record, wrong key or corrupted data"))
+    }
+
+    // For files of records, where the id is only known from the record itself.
+    // It can be trusted once the record decrypts
+    pub fn record_id(record: &str) -> Result<&str> {
+        split(record).map(|(id, _)| id)
+    }
+}
+
+fn split(record: &str) -> Result<(&str, &str)> {
+    record
+        .strip_prefix(ENCRYPTED_PREFIX)
+        .ok_or_else(|| anyhow!("Cache record is not encrypted"))?
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("Encrypted cache record has no id"))
+}
+
+pub(crate) fn is_encrypted(record: &[u8]) -> bool {
+    record.starts_with(ENCRYPTED_PREFIX.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() -> Result<()> {
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        let record = cipher.encrypt("1a2b", b"tokio select macro")?;
+
+        assert!(is_encrypted(record.as_bytes()));
+        assert!(!record.contains("tokio"));
+        assert_eq!(Cipher::record_id(&record)?, "1a2b");
+        assert_eq!(cipher.decrypt("1a2b", &record)?, b"tokio select macro");
+        // Each record has a nonce of its own
+        assert_ne!(cipher.encrypt("1a2b", b"tokio select macro")?, record);
+        Ok(())
+    }
+
+    #[test]
+    fn another_key_cannot_decrypt() -> Result<()> {
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        let other = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        let record = cipher.encrypt("1a2b", b"tokio select macro")?;
+
+        assert!(other.decrypt("1a2b", &record).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn records_only_decrypt_as_their_own_id() -> Result<()> {
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        let record = cipher.encrypt("1a2b", b"tokio select macro")?;
+
+        assert!(cipher.decrypt("3c4d", &record).is_err());
+        // Relabelling the record is caught by the authentication
+        let relabelled = record.replace("1a2b", "3c4d");
+        assert_eq!(Cipher::record_id(&relabelled)?, "3c4d");
+        assert!(cipher.decrypt("3c4d", &relabelled).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_or_plain_records_are_rejected() -> Result<()> {
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        let record = cipher.encrypt("1a2b", b"tokio select macro")?;
+
+        let mut sealed = STANDARD.decode(&record[ENCRYPTED_PREFIX.len() + "1a2b:".len()..])?;
+        if let Some(last) = sealed.last_mut() {
+            *last ^= 1;
+        }
+        let tampered = format!("{}1a2b:{}", ENCRYPTED_PREFIX, STANDARD.encode(sealed));
+
+        assert!(cipher.decrypt("1a2b", &tampered).is_err());
+        assert!(cipher.decrypt("1a2b", "tokio select macro").is_err());
+        assert!(
+            cipher
+                .decrypt("1a2b", &format!("{}1a2b:AAAA", ENCRYPTED_PREFIX))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plain_text_is_only_accepted_while_migrating() -> Result<()> {
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+
+        assert!(cipher.accept_plaintext("Cache file").is_err());
+        assert!(
+            cipher
+                .migrating_plaintext()
+                .accept_plaintext("Cache file")
+                .is_ok()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        assert!(Cipher::from_encoded_key("not base64!").is_err());
+        assert!(Cipher::from_encoded_key(&STANDARD.encode([0u8; 16])).is_err());
+        assert!(Cipher::from_encoded_key(&format!("{}\n", STANDARD.encode([7u8; 32]))).is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+
+use crate::{
+    CacheQuery, CacheSelector, Cipher, Similarity, SimilarityCache, encryption::is_encrypted,
+    in_memory::rank,
+};
+
+// Keeps entries in memory and persists them to a JSONL file, appending on
+// store and rewriting the file when entries are deleted. With a cipher, each
+// line is encrypted on its own
+pub struct FileSimilarityCache {
+    path: PathBuf,
+    cipher: Option<Cipher>,
+    entries: RwLock<Vec<CacheQuery>>,
+}
+
+impl FileSimilarityCache {
+    pub fn open(path: impl Into<PathBuf>, cipher: Option<Cipher>) -> Result<Self> {
+        let path = path.into();
+        let mut entries: Vec<CacheQuery> = Vec::new();
+        let mut plaintext_lines = 0;
+
+        if path.exists() {
+            let file = File::open(&path)
+                .with_context(|| format!("Failed to open cache file {}", path.display()))?;
+
+            for (index, line) in BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let (line, id) = if is_encrypted(line.as_bytes()) {
+                    let cipher = cipher.as_ref().ok_or_else(|| {
+                        anyhow!(
+                            "Cache file {} is encrypted but no encryption key is configured",
+                            path.display()
+                        )
+                    })?;
+                    let id = Cipher::record_id(&line)?.to_string();
+                    let decrypted = cipher.decrypt(&id, &line).with_context(|| {
+                        format!("Failed to decrypt line {} of {}", index + 1, path.display())
+                    })?;
+                    (String::from_utf8(decrypted)?, Some(id))
+                } else {
+                    if let Some(cipher) = &cipher {
+                        cipher.accept_plaintext(format!(
+                            "Line {} of {}",
+                            index + 1,
+                            path.display()
+                        ))?;
+                    }
+                    plaintext_lines += 1;
+                    (line, None)
+                };
+
+                match serde_json::from_str::<CacheQuery>(&line) {
+                    Ok(entry) if id.is_some_and(|id| id != entry.id()) => {
+                        return Err(anyhow!(
+                            "Line {} of {} holds an entry other than the one it was encrypted for",
+                            index + 1,
+                            path.display()
+                        ));
+                    }
+                    Ok(entry) => {
+                        entries.retain(|existing| !existing.has_same_id(&entry));
+                        entries.push(entry);
+                    }
+                    Err(err) => log::warn!(
+                        "Skipping invalid cache entry on line {} of {}: {}",
+                        index + 1,
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        } else if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let cache = Self {
+            path,
+            cipher,
+            entries: RwLock::new(entries),
+        };
+
+        if cache.cipher.is_some() && plaintext_lines > 0 {
+            log::info!(
+                "Encrypting {} plain text entries in {}",
+                plaintext_lines,
+                cache.path.display()
+            );
+            let entries = cache
+                .entries
+                .read()
+                .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+            cache.rewrite(&entries)?;
+        }
+
+        Ok(cache)
+    }
+
+    fn encode(&self, entry: &CacheQuery) -> Result<Vec<u8>> {
+        let line = serde_json::to_vec(entry)?;
+
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.encrypt(&entry.id(), &line)?.into_bytes()),
+            None => Ok(line),
+        }
+    }
+
+    fn rewrite(&self, entries: &[CacheQuery]) -> Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for entry in entries {
+            writer.write_all(&self.encode(entry)?)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        fs::rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[async_trait]
+impl SimilarityCache for FileSimilarityCache {
+    async fn store(&self, query: CacheQuery) -> Result<()> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = self.encode(&query)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+
+        entries.retain(|entry| !entry.has_same_id(&query));
+        entries.push(query);
+
+        Ok(())
+    }
+
+    async fn similarities(
+        &self,
+        query: CacheQuery,
+        significant_params: &[String],
+    ) -> Result<Vec<Similarity>> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        Ok(rank(&entries, &query, significant_params))
+    }
+
+    async fn entries(&self) -> Result<Vec<CacheQuery>> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        Ok(entries.clone())
+    }
+
+    async fn delete(&self, selector: &CacheSelector) -> Result<usize> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        let before = entries.len();
+        entries.retain(|entry| !selector.matches(entry));
+        let deleted = before - entries.len();
+
+        if deleted > 0 {
+            self.rewrite(&entries)?;
+        }
+
+        Ok(deleted)
+    }
+}
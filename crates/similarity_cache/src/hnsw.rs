@@ -0,0 +1,882 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs::{self, File, TryLockError},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+
+use crate::{
+    CacheQuery, CacheSelector, Cipher, Similarity, SimilarityCache, cosine_similarity,
+    encryption::is_encrypted,
+};
+
+// Persist after this many mutations, in addition to on drop
+const PERSIST_EVERY: usize = 32;
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct HnswParams {
+    // Maximum neighbours per node on the upper layers, twice as many on layer 0
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct Node {
+    entry: CacheQuery,
+    // One neighbour list per layer the node lives on
+    neighbors: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
+struct Graph {
+    nodes: Vec<Node>,
+    entry_point: Option<usize>,
+    live: usize,
+}
+
+impl Graph {
+    fn distance(&self, embedding: &[f32], node: usize) -> f32 {
+        1.0 - cosine_similarity(embedding, &self.nodes[node].entry.embedding)
+    }
+
+    fn top_layer(&self, node: usize) -> usize {
+        self.nodes[node].neighbors.len() - 1
+    }
+
+    // Returns up to `ef` nodes closest to `embedding` on `layer`, nearest first
+    fn search_layer(
+        &self,
+        embedding: &[f32],
+        entry_points: &[usize],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited = HashSet::new();
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+
+        for &node in entry_points {
+            if visited.insert(node) {
+                let candidate = Candidate {
+                    distance: self.distance(embedding, node),
+                    node,
+                };
+                candidates.push(Reverse(candidate));
+                results.push(candidate);
+            }
+        }
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            let furthest = results.peek().map(|c: &Candidate| c.distance);
+            if results.len() >= ef && furthest.is_some_and(|d| candidate.distance > d) {
+                break;
+            }
+
+            let Some(neighbors) = self.nodes[candidate.node].neighbors.get(layer) else {
+                continue;
+            };
+
+            for &neighbor in neighbors {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+
+                let distance = self.distance(embedding, neighbor);
+                let furthest = results.peek().map(|c| c.distance);
+                if results.len() < ef || furthest.is_some_and(|d| distance < d) {
+                    let neighbor = Candidate {
+                        distance,
+                        node: neighbor,
+                    };
+                    candidates.push(Reverse(neighbor));
+                    results.push(neighbor);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    // Descends greedily from the entry point to `layer`
+    fn descend(&self, embedding: &[f32], layer: usize) -> Option<Vec<usize>> {
+        let entry_point = self.entry_point?;
+        let mut entry_points = vec![entry_point];
+
+        for current in (layer + 1..=self.top_layer(entry_point)).rev() {
+            if let Some(nearest) = self
+                .search_layer(embedding, &entry_points, 1, current)
+                .first()
+            {
+                entry_points = vec![nearest.node];
+            }
+        }
+
+        Some(entry_points)
+    }
+
+    fn prune(&mut self, node: usize, layer: usize, max: usize) {
+        let embedding = self.nodes[node].entry.embedding.clone();
+        let mut neighbors = self.nodes[node].neighbors[layer]
+            .iter()
+            .map(|&neighbor| Candidate {
+                distance: self.distance(&embedding, neighbor),
+                node: neighbor,
+            })
+            .collect::<Vec<_>>();
+
+        neighbors.sort();
+
+        self.nodes[node].neighbors[layer] = self.select_neighbors(&neighbors, max);
+    }
+
+    // Keeps up to `max` of `candidates`, given nearest first, preferring those
+    // closer to the node than to any neighbour already kept. Links then also
+    // reach out of a cluster instead of only to its densest part, and the rest
+    // of the candidates fill whatever room is left
+    fn select_neighbors(&self, candidates: &[Candidate], max: usize) -> Vec<usize> {
+        let mut selected = Vec::with_capacity(max);
+        let mut pruned = Vec::new();
+
+        for candidate in candidates {
+            if selected.len() >= max {
+                break;
+            }
+            let embedding = &self.nodes[candidate.node].entry.embedding;
+            if selected
+                .iter()
+                .all(|&kept| self.distance(embedding, kept) > candidate.distance)
+            {
+                selected.push(candidate.node);
+            } else {
+                pruned.push(candidate.node);
+            }
+        }
+
+        let room = max - selected.len();
+        selected.extend(pruned.into_iter().take(room));
+        selected
+    }
+
+    fn insert(&mut self, entry: CacheQuery, level: usize, params: &HnswParams) -> usize {
+        let node = self.nodes.len();
+        let embedding = entry.embedding.clone();
+
+        self.nodes.push(Node {
+            entry,
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.live += 1;
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return node;
+        };
+
+        let top = self.top_layer(entry_point);
+        let mut entry_points = self
+            .descend(&embedding, level.min(top))
+            .unwrap_or_else(|| vec![entry_point]);
+
+        for layer in (0..=level.min(top)).rev() {
+            let max = if layer == 0 { params.m * 2 } else { params.m };
+            let found = self.search_layer(&embedding, &entry_points, params.ef_construction, layer);
+
+            let candidates = found
+                .iter()
+                .filter(|candidate| candidate.node != node)
+                .copied()
+                .collect::<Vec<_>>();
+            let neighbors = self.select_neighbors(&candidates, params.m);
+
+            for &neighbor in &neighbors {
+                self.nodes[neighbor].neighbors[layer].push(node);
+                if self.nodes[neighbor].neighbors[layer].len() > max {
+                    self.prune(neighbor, layer, max);
+                }
+            }
+            self.nodes[node].neighbors[layer] = neighbors;
+
+            entry_points = found.iter().map(|candidate| candidate.node).collect();
+        }
+
+        if level > top {
+            self.entry_point = Some(node);
+        }
+
+        node
+    }
+
+    fn search(&self, embedding: &[f32], ef: usize) -> Vec<Candidate> {
+        match self.descend(embedding, 0) {
+            Some(entry_points) => self.search_layer(embedding, &entry_points, ef, 0),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
+struct HnswIndex {
+    params: HnswParams,
+    // A graph per action, so lookups never have to wade through other tools' answers
+    graphs: HashMap<String, Graph>,
+    rng: u64,
+    #[serde(skip)]
+    ids: HashMap<String, (String, usize)>,
+    #[serde(skip)]
+    pending: usize,
+    // Counts mutations, so that snapshots can be told apart by age
+    #[serde(skip)]
+    generation: u64,
+}
+
+impl HnswIndex {
+    fn new(params: HnswParams) -> Self {
+        Self {
+            params,
+            rng: 0x2545f4914f6cdd1d,
+            ..Default::default()
+        }
+    }
+
+    fn rebuild_ids(&mut self) {
+        self.ids = self
+            .graphs
+            .iter()
+            .flat_map(|(action, graph)| {
+                graph
+                    .nodes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, node)| !node.deleted)
+                    .map(move |(index, node)| (node.entry.id(), (action.clone(), index)))
+            })
+            .collect();
+    }
+
+    // xorshift64, enough to draw node levels
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn random_level(&mut self) -> usize {
+        let level_multiplier = 1.0 / (self.params.m.max(2) as f64).ln();
+        (-(1.0 - self.random()).ln() * level_multiplier).floor() as usize
+    }
+
+    fn insert(&mut self, entry: CacheQuery) {
+        let id = entry.id();
+        self.remove(&id);
+
+        let level = self.random_level();
+        let params = self.params;
+        let action = entry.action.clone();
+        let node = self
+            .graphs
+            .entry(action.clone())
+            .or_default()
+            .insert(entry, level, &params);
+
+        self.ids.insert(id, (action, node));
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        let Some((action, node)) = self.ids.remove(id) else {
+            return false;
+        };
+
+        if let Some(graph) = self.graphs.get_mut(&action) {
+            graph.nodes[node].deleted = true;
+            graph.live -= 1;
+        }
+
+        true
+    }
+
+    // Rebuilds graphs where tombstones outnumber live nodes
+    fn compact(&mut self) {
+        let actions = self
+            .graphs
+            .iter()
+            .filter(|(_, graph)| graph.nodes.len() > 64 && graph.nodes.len() > graph.live * 2)
+            .map(|(action, _)| action.clone())
+            .collect::<Vec<_>>();
+
+        for action in actions {
+            let Some(graph) = self.graphs.remove(&action) else {
+                continue;
+            };
+
+            log::info!(
+                "Compacting HNSW graph for {} ({} live of {} nodes)",
+                action,
+                graph.live,
+                graph.nodes.len()
+            );
+
+            for node in graph.nodes.into_iter().filter(|node| !node.deleted) {
+                let level = self.random_level();
+                let params = self.params;
+                self.graphs
+                    .entry(action.clone())
+                    .or_default()
+                    .insert(node.entry, level, &params);
+            }
+        }
+
+        self.rebuild_ids();
+    }
+
+    fn live_entries(&self) -> impl Iterator<Item = &CacheQuery> {
+        self.graphs.values().flat_map(|graph| {
+            graph
+                .nodes
+                .iter()
+                .filter(|node| !node.deleted)
+                .map(|node| &node.entry)
+        })
+    }
+}
+
+// The whole index is encrypted as a single record
+const INDEX_RECORD_ID: &str = "hnsw-index";
+
+// Writes snapshots of the index to its file, which only one process may do at a time
+struct Persistence {
+    path: PathBuf,
+    cipher: Option<Cipher>,
+    // Locked for as long as the cache is open
+    _lock: File,
+    // The generation of the newest snapshot written, so that a slow write never
+    // replaces a newer one
+    written: Mutex<u64>,
+}
+
+impl Persistence {
+    fn write(&self, generation: u64, index: &HnswIndex) -> Result<()> {
+        let mut written = self
+            .written
+            .lock()
+            .map_err(|_| anyhow!("HNSW persistence lock poisoned"))?;
+        if *written >= generation {
+            return Ok(());
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        let mut contents = serde_json::to_vec(index)?;
+        if let Some(cipher) = &self.cipher {
+            contents = cipher.encrypt(INDEX_RECORD_ID, &contents)?.into_bytes();
+        }
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)?;
+        *written = generation;
+
+        Ok(())
+    }
+
+    fn written(&self) -> u64 {
+        self.written.lock().map(|written| *written).unwrap_or(0)
+    }
+}
+
+enum Storage {
+    Memory,
+    File(Arc<Persistence>),
+    // Another process holds the lock on this file, so nothing is written to it
+    ReadOnly(PathBuf),
+}
+
+// Approximate nearest-neighbour cache backed by a Hierarchical Navigable Small World graph
+pub struct HnswSimilarityCache {
+    storage: Storage,
+    index: RwLock<HnswIndex>,
+}
+
+impl HnswSimilarityCache {
+    pub fn new(params: HnswParams) -> Self {
+        Self {
+            storage: Storage::Memory,
+            index: RwLock::new(HnswIndex::new(params)),
+        }
+    }
+
+    // Opens the index at `path` read-only when another process, such as a
+    // second server or the `cache` command, already has it open
+    pub fn open(
+        path: impl Into<PathBuf>,
+        params: HnswParams,
+        cipher: Option<Cipher>,
+    ) -> Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open {}", PathBuf::from(&lock_path).display()))?;
+        let locked = match lock.try_lock() {
+            Ok(()) => true,
+            Err(TryLockError::WouldBlock) => {
+                log::warn!(
+                    "HNSW index {} is open in another process, new entries will not be saved",
+                    path.display()
+                );
+                false
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        };
+
+        let mut encrypt = false;
+        let index = if path.exists() {
+            let mut contents = fs::read(&path)
+                .with_context(|| format!("Failed to read HNSW index {}", path.display()))?;
+            if is_encrypted(&contents) {
+                let cipher = cipher.as_ref().ok_or_else(|| {
+                    anyhow!(
+                        "HNSW index {} is encrypted but no encryption key is configured",
+                        path.display()
+                    )
+                })?;
+                contents = cipher.decrypt(INDEX_RECORD_ID, str::from_utf8(&contents)?)?;
+            } else if let Some(cipher) = &cipher {
+                cipher.accept_plaintext(format!("HNSW index {}", path.display()))?;
+                if !locked {
+                    return Err(anyhow!(
+                        "HNSW index {} is open in another process, stop it to encrypt the index",
+                        path.display()
+                    ));
+                }
+                encrypt = true;
+            }
+            let mut index: HnswIndex = serde_json::from_slice(&contents)
+                .with_context(|| format!("Failed to parse HNSW index {}", path.display()))?;
+            index.params.ef_search = params.ef_search;
+            index.rebuild_ids();
+            index
+        } else {
+            HnswIndex::new(params)
+        };
+
+        let storage = if locked {
+            Storage::File(Arc::new(Persistence {
+                path,
+                cipher,
+                _lock: lock,
+                written: Mutex::new(0),
+            }))
+        } else {
+            Storage::ReadOnly(path)
+        };
+        let cache = Self {
+            storage,
+            index: RwLock::new(index),
+        };
+
+        if encrypt {
+            log::info!("Encrypting plain text HNSW index");
+            cache.mutate(|index| index.generation += 1)?;
+            cache.persist()?;
+        }
+
+        Ok(cache)
+    }
+
+    // Writes the index now, waiting for the file
+    pub fn persist(&self) -> Result<()> {
+        let Storage::File(persistence) = &self.storage else {
+            return Ok(());
+        };
+
+        let (generation, snapshot) = {
+            let index = self
+                .index
+                .read()
+                .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+            (index.generation, index.clone())
+        };
+
+        persistence.write(generation, &snapshot)
+    }
+
+    // Applies a mutation, and every so often, or when `flush` is set, writes
+    // a snapshot of the index on a blocking thread, away from the lock
+    fn mutated(&self, index: &mut HnswIndex, flush: bool) -> Result<()> {
+        index.generation += 1;
+        index.pending += 1;
+        if !flush && index.pending < PERSIST_EVERY {
+            return Ok(());
+        }
+
+        index.compact();
+        index.pending = 0;
+
+        let Storage::File(persistence) = &self.storage else {
+            return Ok(());
+        };
+        let persistence = persistence.clone();
+        let generation = index.generation;
+        let snapshot = index.clone();
+        let write = move || {
+            if let Err(err) = persistence.write(generation, &snapshot) {
+                log::error!("Failed to persist HNSW index: {}", err);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+
+        Ok(())
+    }
+
+    fn mutate<T>(&self, mutation: impl FnOnce(&mut HnswIndex) -> T) -> Result<T> {
+        if let Storage::ReadOnly(path) = &self.storage {
+            return Err(anyhow!(
+                "HNSW index {} is open in another process",
+                path.display()
+            ));
+        }
+
+        let mut index = self
+            .index
+            .write()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        Ok(mutation(&mut index))
+    }
+}
+
+impl Drop for HnswSimilarityCache {
+    fn drop(&mut self) {
+        let Storage::File(persistence) = &self.storage else {
+            return;
+        };
+        let generation = self.index.read().map(|index| index.generation).unwrap_or(0);
+        if persistence.written() < generation
+            && let Err(err) = self.persist()
+        {
+            log::error!("Failed to persist HNSW index: {}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl SimilarityCache for HnswSimilarityCache {
+    async fn store(&self, query: CacheQuery) -> Result<()> {
+        self.mutate(|index| {
+            index.insert(query);
+            self.mutated(index, false)
+        })?
+    }
+
+    async fn similarities(
+        &self,
+        query: CacheQuery,
+        significant_params: &[String],
+    ) -> Result<Vec<Similarity>> {
+        let index = self
+            .index
+            .read()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        let Some(graph) = index.graphs.get(&query.action) else {
+            return Ok(Vec::new());
+        };
+
+        // The exact entry is looked up directly, the rest comes from the graph
+        let exact = index
+            .ids
+            .get(&query.id())
+            .filter(|(action, _)| *action == query.action)
+            .map(|(_, node)| *node);
+        let candidates = graph.search(&query.embedding, index.params.ef_search);
+
+        let mut similarities = exact
+            .into_iter()
+            .chain(
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.node)
+                    .filter(|node| Some(*node) != exact),
+            )
+            .filter(|node| !graph.nodes[*node].deleted)
+            .filter_map(|node| {
+                let entry = &graph.nodes[node].entry;
+                let reason = entry.match_reason(&query, significant_params)?;
+                let score = if reason.exact_text {
+                    1.0
+                } else {
+                    cosine_similarity(&entry.embedding, &query.embedding)
+                };
+
+                Some(Similarity {
+                    query: entry.clone(),
+                    score,
+                    reason,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        similarities.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+        Ok(similarities)
+    }
+
+    async fn entries(&self) -> Result<Vec<CacheQuery>> {
+        let index = self
+            .index
+            .read()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        let mut entries = index.live_entries().cloned().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.created_at);
+
+        Ok(entries)
+    }
+
+    async fn delete(&self, selector: &CacheSelector) -> Result<usize> {
+        self.mutate(|index| {
+            let ids = index
+                .live_entries()
+                .filter(|entry| selector.matches(entry))
+                .map(|entry| entry.id())
+                .collect::<Vec<_>>();
+
+            let deleted = ids.iter().filter(|id| index.remove(id)).count();
+            if deleted > 0 {
+                // Deletions are rare and explicit, so they are persisted straight away
+                self.mutated(index, true)?;
+            }
+
+            Ok(deleted)
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::InMemorySimilarityCache;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        // Clustered around one of `centroids`, as embeddings of related questions are
+        fn embedding(&mut self, centroids: &[Vec<f32>]) -> Vec<f32> {
+            let centroid = &centroids[(self.next() * centroids.len() as f32) as usize];
+            let vector = centroid
+                .iter()
+                .map(|c| c + (self.next() - 0.5) * 0.5)
+                .collect::<Vec<_>>();
+            let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+            vector.into_iter().map(|v| v / norm).collect()
+        }
+    }
+
+    fn entry(text: String, embedding: Vec<f32>) -> CacheQuery {
+        CacheQuery {
+            action: "search".into(),
+            text,
+            params: None,
+            embedding,
+            results: json!({ "answer": 42 }),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("similarity_cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("index.hnsw")
+    }
+
+    #[tokio::test]
+    async fn recall_matches_brute_force() -> Result<()> {
+        let mut random = Random(0x9e3779b97f4a7c15);
+        let centroids = (0..32)
+            .map(|_| (0..64).map(|_| random.next() - 0.5).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let hnsw = HnswSimilarityCache::new(HnswParams::default());
+        let flat = InMemorySimilarityCache::new();
+        for index in 0..1000 {
+            let entry = entry(format!("entry {}", index), random.embedding(&centroids));
+            hnsw.store(entry.clone()).await?;
+            flat.store(entry).await?;
+        }
+
+        let queries = 100;
+        let mut found = 0;
+        for index in 0..queries {
+            let query = entry(format!("query {}", index), random.embedding(&centroids));
+            let expected = flat.similarities(query.clone(), &[]).await?;
+            let actual = hnsw.similarities(query, &[]).await?;
+            if actual.first().map(|s| &s.query.text) == expected.first().map(|s| &s.query.text) {
+                found += 1;
+            }
+        }
+
+        let recall = found as f32 / queries as f32;
+        assert!(recall >= 0.95, "recall@1 of {}", recall);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn entries_survive_a_reopen() -> Result<()> {
+        let path = temp_path("reopen");
+        {
+            let cache = HnswSimilarityCache::open(&path, HnswParams::default(), None)?;
+            for index in 0..PERSIST_EVERY + 5 {
+                let text = format!("question {}", index);
+                cache
+                    .store(entry(text.clone(), crate::embed(&text)))
+                    .await?;
+            }
+        }
+
+        let cache = HnswSimilarityCache::open(&path, HnswParams::default(), None)?;
+        assert_eq!(cache.entries().await?.len(), PERSIST_EVERY + 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_second_process_opens_the_index_read_only() -> Result<()> {
+        let path = temp_path("read-only");
+        let owner = HnswSimilarityCache::open(&path, HnswParams::default(), None)?;
+        owner
+            .store(entry("tokio".into(), crate::embed("tokio")))
+            .await?;
+        owner.persist()?;
+
+        // Locks are per open file, so a second handle stands in for another process
+        let reader = HnswSimilarityCache::open(&path, HnswParams::default(), None)?;
+        assert_eq!(reader.entries().await?.len(), 1);
+        assert!(
+            reader
+                .store(entry("serde".into(), crate::embed("serde")))
+                .await
+                .is_err()
+        );
+        assert!(reader.delete(&CacheSelector::All).await.is_err());
+
+        drop(reader);
+        assert_eq!(owner.entries().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn an_encrypted_index_needs_its_key() -> Result<()> {
+        let path = temp_path("encrypted");
+        let key = Cipher::generate_key();
+        {
+            let cache = HnswSimilarityCache::open(
+                &path,
+                HnswParams::default(),
+                Some(Cipher::from_encoded_key(&key)?),
+            )?;
+            cache
+                .store(entry("tokio".into(), crate::embed("tokio")))
+                .await?;
+        }
+
+        assert!(!fs::read_to_string(&path)?.contains("tokio"));
+        assert!(HnswSimilarityCache::open(&path, HnswParams::default(), None).is_err());
+        let cache = HnswSimilarityCache::open(
+            &path,
+            HnswParams::default(),
+            Some(Cipher::from_encoded_key(&key)?),
+        )?;
+        assert_eq!(cache.entries().await?[0].results, json!({ "answer": 42 }));
+        assert_ne!(cache.entries().await?[0].results, Value::Null);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_plain_index_is_only_encrypted_on_request() -> Result<()> {
+        let path = temp_path("migrated");
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        {
+            let cache = HnswSimilarityCache::open(&path, HnswParams::default(), None)?;
+            cache
+                .store(entry("tokio".into(), crate::embed("tokio")))
+                .await?;
+        }
+
+        assert!(
+            HnswSimilarityCache::open(&path, HnswParams::default(), Some(cipher.clone())).is_err()
+        );
+        assert!(fs::read_to_string(&path)?.contains("tokio"));
+
+        drop(HnswSimilarityCache::open(
+            &path,
+            HnswParams::default(),
+            Some(cipher.clone().migrating_plaintext()),
+        )?);
+        assert!(!fs::read_to_string(&path)?.contains("tokio"));
+        let cache = HnswSimilarityCache::open(&path, HnswParams::default(), Some(cipher))?;
+        assert_eq!(cache.entries().await?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::RwLock;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+
+use crate::{CacheQuery, CacheSelector, Similarity, SimilarityCache, cosine_similarity};
+
+// Scores every entry against `query`, keeping those with a matching action and parameters
+pub(crate) fn rank(
+    entries: &[CacheQuery],
+    query: &CacheQuery,
+    significant_params: &[String],
+) -> Vec<Similarity> {
+    closest(entries, query, significant_params, usize::MAX)
+}
+
+// Like `rank`, but only the `limit` best entries are kept, and cloned
+pub(crate) fn closest<'a>(
+    entries: impl IntoIterator<Item = &'a CacheQuery>,
+    query: &CacheQuery,
+    significant_params: &[String],
+    limit: usize,
+) -> Vec<Similarity> {
+    let mut scored = entries
+        .into_iter()
+        .filter_map(|entry| {
+            let reason = entry.match_reason(query, significant_params)?;
+            let score = if reason.exact_text {
+                1.0
+            } else {
+                cosine_similarity(&entry.embedding, &query.embedding)
+            };
+
+            Some((entry, score, reason))
+        })
+        .collect::<Vec<_>>();
+
+    scored.sort_by(|(_, a, _), (_, b, _)| b.total_cmp(a));
+    scored.truncate(limit);
+
+    scored
+        .into_iter()
+        .map(|(entry, score, reason)| Similarity {
+            query: entry.clone(),
+            score,
+            reason,
+        })
+        .collect()
+}
+
+#[derive(Default)]
+pub struct InMemorySimilarityCache {
+    entries: RwLock<Vec<CacheQuery>>,
+}
+
+impl InMemorySimilarityCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SimilarityCache for InMemorySimilarityCache {
+    async fn store(&self, query: CacheQuery) -> Result<()> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        entries.retain(|entry| !entry.has_same_id(&query));
+        entries.push(query);
+
+        Ok(())
+    }
+
+    async fn similarities(
+        &self,
+        query: CacheQuery,
+        significant_params: &[String],
+    ) -> Result<Vec<Similarity>> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        Ok(rank(&entries, &query, significant_params))
+    }
+
+    async fn entries(&self) -> Result<Vec<CacheQuery>> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        Ok(entries.clone())
+    }
+
+    async fn delete(&self, selector: &CacheSelector) -> Result<usize> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))?;
+
+        let before = entries.len();
+        entries.retain(|entry| !selector.matches(entry));
+
+        Ok(before - entries.len())
+    }
+}
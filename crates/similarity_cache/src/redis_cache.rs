@@ -0,0 +1,578 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use serde_json::Value;
+
+use crate::{
+    CacheQuery, CacheSelector, Cipher, Similarity, SimilarityCache, encryption::is_encrypted,
+    in_memory::closest,
+};
+
+const DEFAULT_PREFIX: &str = "perplexity-mcp:cache";
+
+// Answers fetched per lookup, closest first, in case the best have expired
+const CANDIDATES: usize = 4;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct RedisCacheConfig {
+    pub url: String,
+    pub prefix: String,
+    // Entries expire after this many seconds, or never when unset
+    pub ttl_seconds: Option<u64>,
+}
+
+impl Default for RedisCacheConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1:6379".into(),
+            prefix: DEFAULT_PREFIX.into(),
+            ttl_seconds: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ActionIndex {
+    // The version of the action in Redis when the index was last synced
+    version: Option<u64>,
+    // Entries without their answers, by id
+    entries: HashMap<String, CacheQuery>,
+}
+
+// Shares entries through any server speaking the Redis protocol. Each entry is
+// a JSON string under `{prefix}:entry:{id}`, its text and embedding also under
+// `{prefix}:meta:{id}`, and the ids of an action are kept in a set whose
+// version is bumped on every change.
+//
+// Each server keeps a local index of the embeddings, fetching the metadata of
+// new entries when the version moves, so that a lookup costs a version check
+// and the few closest answers. With a cipher, entries are encrypted before they
+// leave the process
+pub struct RedisSimilarityCache {
+    connection: ConnectionManager,
+    prefix: String,
+    ttl_seconds: Option<u64>,
+    cipher: Option<Cipher>,
+    index: Mutex<HashMap<String, ActionIndex>>,
+}
+
+impl RedisSimilarityCache {
+    pub async fn connect(config: &RedisCacheConfig, cipher: Option<Cipher>) -> Result<Self> {
+        let client = redis::Client::open(config.url.as_str())
+            .with_context(|| format!("Invalid Redis URL {}", config.url))?;
+        let connection = client
+            .get_connection_manager()
+            .await
+            .with_context(|| format!("Failed to connect to Redis at {}", config.url))?;
+
+        Ok(Self {
+            connection,
+            prefix: config.prefix.clone(),
+            ttl_seconds: config.ttl_seconds,
+            cipher,
+            index: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Values are encrypted for their key, so that they cannot be moved to another
+    fn encode(&self, key: &str, entry: &CacheQuery) -> Result<String> {
+        let value = serde_json::to_string(entry)?;
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(key, value.as_bytes()),
+            None => Ok(value),
+        }
+    }
+
+    // Plain text entries, stored before encryption was enabled, are only read
+    // with a cipher by `cache encrypt`
+    fn decode(&self, key: &str, value: &str) -> Result<CacheQuery> {
+        if !is_encrypted(value.as_bytes()) {
+            if let Some(cipher) = &self.cipher {
+                cipher.accept_plaintext(format!("Entry {}", key))?;
+            }
+            return Ok(serde_json::from_str(value)?);
+        }
+
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| anyhow!("Entry is encrypted but no encryption key is configured"))?;
+        Ok(serde_json::from_slice(&cipher.decrypt(key, value)?)?)
+    }
+
+    fn entry_key(&self, id: &str) -> String {
+        format!("{}:entry:{}", self.prefix, id)
+    }
+
+    fn meta_key(&self, id: &str) -> String {
+        format!("{}:meta:{}", self.prefix, id)
+    }
+
+    fn action_key(&self, action: &str) -> String {
+        format!("{}:action:{}", self.prefix, action)
+    }
+
+    fn version_key(&self, action: &str) -> String {
+        format!("{}:version:{}", self.prefix, action)
+    }
+
+    fn actions_key(&self) -> String {
+        format!("{}:actions", self.prefix)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<String, ActionIndex>>> {
+        self.index
+            .lock()
+            .map_err(|_| anyhow!("Similarity cache lock poisoned"))
+    }
+
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut connection = self.connection.clone();
+        Ok(redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut connection)
+            .await?)
+    }
+
+    // Brings the local index of `action` up to date, fetching only the
+    // metadata of entries it has not seen yet
+    async fn sync(&self, action: &str) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let action_key = self.action_key(action);
+
+        let version: Option<u64> = connection.get(self.version_key(action)).await?;
+        let known = match self.lock()?.get(action) {
+            Some(local) if local.version.is_some() && local.version == version => return Ok(()),
+            Some(local) => local.entries.keys().cloned().collect::<HashSet<_>>(),
+            None => HashSet::new(),
+        };
+
+        let ids: HashSet<String> = connection.smembers(&action_key).await?;
+        let missing = ids.difference(&known).cloned().collect::<Vec<_>>();
+        let metas = self
+            .mget(
+                &missing
+                    .iter()
+                    .map(|id| self.meta_key(id))
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+
+        let mut fetched = Vec::new();
+        let mut without_meta = Vec::new();
+        for (id, value) in missing.into_iter().zip(metas) {
+            match value {
+                Some(value) => fetched.push((self.meta_key(&id), id, value)),
+                None => without_meta.push(id),
+            }
+        }
+
+        // Entries stored without metadata, by an older server, are read whole
+        let entries = self
+            .mget(
+                &without_meta
+                    .iter()
+                    .map(|id| self.entry_key(id))
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+        let mut expired = Vec::new();
+        for (id, value) in without_meta.into_iter().zip(entries) {
+            match value {
+                Some(value) => fetched.push((self.entry_key(&id), id, value)),
+                None => expired.push(id),
+            }
+        }
+
+        let mut entries = Vec::new();
+        for (key, id, value) in fetched {
+            match self.decode(&key, &value) {
+                Ok(entry) => entries.push((id, without_results(entry))),
+                // Left in place, as another server may hold the key to it
+                Err(err) => log::warn!("Skipping unreadable Redis cache entry {}: {}", id, err),
+            }
+        }
+
+        // Entries expire on their own, their ids are cleaned up lazily
+        if !expired.is_empty() {
+            let _: () = connection.srem(&action_key, &expired).await?;
+        }
+
+        let mut index = self.lock()?;
+        let local = index.entry(action.to_string()).or_default();
+        local
+            .entries
+            .retain(|id, _| ids.contains(id) && !expired.contains(id));
+        local.entries.extend(entries);
+        local.version = version;
+
+        Ok(())
+    }
+
+    // Drops entries found to have expired
+    async fn forget(&self, action: &str, ids: &[String]) -> Result<()> {
+        if let Some(local) = self.lock()?.get_mut(action) {
+            for id in ids {
+                local.entries.remove(id);
+            }
+        }
+
+        let mut connection = self.connection.clone();
+        let _: () = connection.srem(self.action_key(action), ids).await?;
+
+        Ok(())
+    }
+
+    // Every entry of `action`, answers included, for administration
+    async fn action_entries(&self, action: &str) -> Result<Vec<CacheQuery>> {
+        let mut connection = self.connection.clone();
+        let action_key = self.action_key(action);
+
+        let ids: Vec<String> = connection.smembers(&action_key).await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let keys = ids.iter().map(|id| self.entry_key(id)).collect::<Vec<_>>();
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut connection)
+            .await?;
+
+        let mut entries = Vec::new();
+        let mut expired = Vec::new();
+        for ((id, key), value) in ids.iter().zip(&keys).zip(values) {
+            match value.map(|value| self.decode(key, &value)) {
+                Some(Ok(entry)) => entries.push(entry),
+                // Left in place, as another server may hold the key to it
+                Some(Err(err)) => {
+                    log::warn!("Skipping unreadable Redis cache entry {}: {}", id, err)
+                }
+                None => expired.push(id.clone()),
+            }
+        }
+
+        // Entries expire on their own, their ids are cleaned up lazily
+        if !expired.is_empty() {
+            let _: () = connection.srem(&action_key, &expired).await?;
+        }
+
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl SimilarityCache for RedisSimilarityCache {
+    async fn store(&self, query: CacheQuery) -> Result<()> {
+        let mut connection = self.connection.clone();
+        let id = query.id();
+        let (entry_key, meta_key) = (self.entry_key(&id), self.meta_key(&id));
+        let value = self.encode(&entry_key, &query)?;
+        let action = query.action.clone();
+        let meta = without_results(query);
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        for (key, value) in [
+            (entry_key, value),
+            (meta_key.clone(), self.encode(&meta_key, &meta)?),
+        ] {
+            match self.ttl_seconds {
+                Some(ttl) => pipeline.set_ex(key, value, ttl).ignore(),
+                None => pipeline.set(key, value).ignore(),
+            };
+        }
+        pipeline
+            .sadd(self.action_key(&action), &id)
+            .ignore()
+            .sadd(self.actions_key(), &action)
+            .ignore()
+            .incr(self.version_key(&action), 1);
+
+        let (version,): (u64,) = pipeline.query_async(&mut connection).await?;
+
+        // Unless another server changed the action meanwhile, the index is
+        // still in sync
+        let mut index = self.lock()?;
+        let local = index.entry(action).or_default();
+        local.entries.insert(id, meta);
+        if local.version.is_some_and(|known| known + 1 == version) {
+            local.version = Some(version);
+        }
+
+        Ok(())
+    }
+
+    async fn similarities(
+        &self,
+        query: CacheQuery,
+        significant_params: &[String],
+    ) -> Result<Vec<Similarity>> {
+        self.sync(&query.action).await?;
+        let candidates = match self.lock()?.get(&query.action) {
+            Some(local) => closest(
+                local.entries.values(),
+                &query,
+                significant_params,
+                CANDIDATES,
+            ),
+            None => Vec::new(),
+        };
+
+        let keys = candidates
+            .iter()
+            .map(|candidate| self.entry_key(&candidate.query.id()))
+            .collect::<Vec<_>>();
+        let values = self.mget(&keys).await?;
+
+        let mut similarities = Vec::new();
+        let mut expired = Vec::new();
+        for ((mut similarity, key), value) in candidates.into_iter().zip(&keys).zip(values) {
+            match value.map(|value| self.decode(key, &value)) {
+                Some(Ok(entry)) => {
+                    similarity.query = entry;
+                    similarities.push(similarity);
+                }
+                Some(Err(err)) => log::warn!("Skipping unreadable Redis cache entry: {}", err),
+                None => expired.push(similarity.query.id()),
+            }
+        }
+        if !expired.is_empty() {
+            self.forget(&query.action, &expired).await?;
+        }
+
+        Ok(similarities)
+    }
+
+    async fn entries(&self) -> Result<Vec<CacheQuery>> {
+        let mut connection = self.connection.clone();
+        let actions: Vec<String> = connection.smembers(self.actions_key()).await?;
+
+        let mut entries = Vec::new();
+        for action in actions {
+            entries.extend(self.action_entries(&action).await?);
+        }
+        entries.sort_by_key(|entry| entry.created_at);
+
+        Ok(entries)
+    }
+
+    async fn delete(&self, selector: &CacheSelector) -> Result<usize> {
+        let mut connection = self.connection.clone();
+        let actions: Vec<String> = connection.smembers(self.actions_key()).await?;
+
+        // Selectors only look at metadata, so answers need not be fetched
+        let mut deleted = Vec::new();
+        for action in actions {
+            self.sync(&action).await?;
+            if let Some(local) = self.lock()?.get(&action) {
+                deleted.extend(
+                    local
+                        .entries
+                        .iter()
+                        .filter(|(_, entry)| selector.matches(entry))
+                        .map(|(id, _)| (action.clone(), id.clone())),
+                );
+            }
+        }
+
+        if deleted.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pipeline = redis::pipe();
+        for (action, id) in &deleted {
+            pipeline
+                .del(self.entry_key(id))
+                .ignore()
+                .del(self.meta_key(id))
+                .ignore()
+                .srem(self.action_key(action), id)
+                .ignore()
+                .incr(self.version_key(action), 1)
+                .ignore();
+        }
+        let _: () = pipeline.query_async(&mut connection).await?;
+
+        let mut index = self.lock()?;
+        for (action, id) in &deleted {
+            if let Some(local) = index.get_mut(action) {
+                local.entries.remove(id);
+            }
+        }
+
+        Ok(deleted.len())
+    }
+}
+
+fn without_results(entry: CacheQuery) -> CacheQuery {
+    CacheQuery {
+        results: Value::Null,
+        ..entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process, time::Duration};
+
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+
+    // Run against the Redis at REDIS_URL, such as a local `redis-server`, and
+    // skipped without one. Each test keeps to a prefix of its own
+    async fn connect(
+        name: &str,
+        ttl_seconds: Option<u64>,
+        cipher: Option<Cipher>,
+    ) -> Result<Option<RedisSimilarityCache>> {
+        let Ok(url) = env::var("REDIS_URL") else {
+            eprintln!("REDIS_URL is not set, skipping");
+            return Ok(None);
+        };
+        let config = RedisCacheConfig {
+            url,
+            prefix: format!("perplexity-mcp-test:{}:{}", name, process::id()),
+            ttl_seconds,
+        };
+
+        let cache = RedisSimilarityCache::connect(&config, cipher).await?;
+        cache.delete(&CacheSelector::All).await?;
+        Ok(Some(cache))
+    }
+
+    fn entry(text: &str) -> CacheQuery {
+        CacheQuery {
+            action: "search".into(),
+            text: text.into(),
+            params: None,
+            embedding: crate::embed(text),
+            results: json!({ "answer": text }),
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn closest(cache: &RedisSimilarityCache, text: &str) -> Result<Option<String>> {
+        Ok(cache
+            .similarities(entry(text), &[])
+            .await?
+            .first()
+            .filter(|similarity| similarity.score > 0.99)
+            .map(|similarity| similarity.query.results["answer"].to_string()))
+    }
+
+    #[tokio::test]
+    async fn stored_answers_are_found_by_similarity() -> Result<()> {
+        let Some(cache) = connect("similarities", None, None).await? else {
+            return Ok(());
+        };
+
+        cache.store(entry("tokio select macro")).await?;
+        cache.store(entry("serde derive attributes")).await?;
+
+        let similarities = cache.similarities(entry("tokio select macro"), &[]).await?;
+        assert_eq!(similarities.len(), 2);
+        assert_eq!(similarities[0].query.text, "tokio select macro");
+        // Answers are fetched for the candidates, not kept in the index
+        assert_eq!(
+            similarities[0].query.results,
+            json!({ "answer": "tokio select macro" })
+        );
+        assert!(similarities[0].score > similarities[1].score);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_their_ttl() -> Result<()> {
+        let Some(cache) = connect("expiry", Some(1), None).await? else {
+            return Ok(());
+        };
+
+        cache.store(entry("tokio select macro")).await?;
+        assert!(closest(&cache, "tokio select macro").await?.is_some());
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(closest(&cache, "tokio select macro").await?, None);
+        assert!(cache.entries().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_entries_are_gone() -> Result<()> {
+        let Some(cache) = connect("delete", None, None).await? else {
+            return Ok(());
+        };
+
+        let tokio = entry("tokio select macro");
+        cache.store(tokio.clone()).await?;
+        cache.store(entry("serde derive attributes")).await?;
+
+        assert_eq!(cache.delete(&CacheSelector::Id(tokio.id())).await?, 1);
+        assert_eq!(closest(&cache, "tokio select macro").await?, None);
+        assert_eq!(cache.entries().await?.len(), 1);
+
+        assert_eq!(cache.delete(&CacheSelector::All).await?, 1);
+        assert!(cache.entries().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_see_each_others_changes() -> Result<()> {
+        let (Some(first), Some(second)) = (
+            connect("shared", None, None).await?,
+            connect("shared", None, None).await?,
+        ) else {
+            return Ok(());
+        };
+
+        // The first server's index is synced before the second stores anything
+        assert_eq!(closest(&first, "tokio select macro").await?, None);
+        second.store(entry("tokio select macro")).await?;
+        assert!(closest(&first, "tokio select macro").await?.is_some());
+
+        first.store(entry("serde derive attributes")).await?;
+        assert!(closest(&second, "serde derive attributes").await?.is_some());
+
+        second.delete(&CacheSelector::All).await?;
+        assert_eq!(closest(&first, "tokio select macro").await?, None);
+        assert_eq!(closest(&first, "serde derive attributes").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plain_text_is_only_read_while_migrating() -> Result<()> {
+        let cipher = Cipher::from_encoded_key(&Cipher::generate_key())?;
+        let (Some(plain), Some(encrypted), Some(migrating)) = (
+            connect("plaintext", None, None).await?,
+            connect("plaintext", None, Some(cipher.clone())).await?,
+            connect("plaintext", None, Some(cipher.migrating_plaintext())).await?,
+        ) else {
+            return Ok(());
+        };
+
+        plain.store(entry("tokio select macro")).await?;
+
+        // Skipped rather than deleted, for `cache encrypt` to pick up
+        assert!(encrypted.entries().await?.is_empty());
+        assert_eq!(closest(&encrypted, "tokio select macro").await?, None);
+        assert_eq!(migrating.entries().await?.len(), 1);
+
+        // Stored again, the entry is encrypted
+        migrating.store(entry("tokio select macro")).await?;
+        assert!(closest(&encrypted, "tokio select macro").await?.is_some());
+        assert!(plain.entries().await?.is_empty());
+        Ok(())
+    }
+}
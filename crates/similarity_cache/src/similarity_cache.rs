@@ -1,27 +1,138 @@
+mod admin;
+mod embedding;
+mod encryption;
+mod file;
+mod hnsw;
+mod in_memory;
+#[cfg(feature = "redis")]
+mod redis_cache;
+
+use std::fmt;
+
 use anyhow::Result;
 use async_trait::async_trait;
-use serde_json::Value;
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+pub use crate::admin::{CacheSelector, export_jsonl, export_recent_jsonl, import_jsonl, search};
+pub use crate::embedding::{cosine_similarity, embed};
+pub use crate::encryption::{Cipher, EncryptionConfig};
+pub use crate::file::FileSimilarityCache;
+pub use crate::hnsw::{HnswParams, HnswSimilarityCache};
+pub use crate::in_memory::InMemorySimilarityCache;
+#[cfg(feature = "redis")]
+pub use crate::redis_cache::{RedisCacheConfig, RedisSimilarityCache};
 
 #[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct CacheQuery {
     pub action: String,
     pub text: String,
     pub params: Option<Value>,
+    #[serde(default)]
     pub embedding: Vec<f32>,
     pub results: Value,
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl CacheQuery {
+    // Entries asking the same thing share an id, so storing one again replaces it
+    pub fn id(&self) -> String {
+        let params = self
+            .params
+            .as_ref()
+            .map(|params| params.to_string())
+            .unwrap_or_default();
+        let key = format!("{}\0{}\0{}", self.action, self.text, params);
+
+        format!("{:016x}", embedding::hash(key.as_bytes()))
+    }
+
+    // Same as comparing ids, without hashing
+    pub(crate) fn has_same_id(&self, other: &CacheQuery) -> bool {
+        self.action == other.action && self.text == other.text && self.params == other.params
+    }
+
+    fn param(&self, name: &str) -> &Value {
+        self.params
+            .as_ref()
+            .and_then(|params| params.get(name))
+            .unwrap_or(&Value::Null)
+    }
+
+    /// Explains why this entry may answer `query`, or returns `None` when the
+    /// action or any of the significant parameters differ.
+    pub fn match_reason(
+        &self,
+        query: &CacheQuery,
+        significant_params: &[String],
+    ) -> Option<MatchReason> {
+        if self.action != query.action {
+            return None;
+        }
+
+        let mut params = Map::new();
+        for name in significant_params {
+            let value = self.param(name);
+            if value != query.param(name) {
+                return None;
+            }
+            params.insert(name.clone(), value.clone());
+        }
+
+        Some(MatchReason {
+            action: self.action.clone(),
+            params,
+            exact_text: self.text == query.text,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchReason {
+    pub action: String,
+    pub params: Map<String, Value>,
+    pub exact_text: bool,
+}
+
+impl fmt::Display for MatchReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "action `{}`", self.action)?;
+        if self.exact_text {
+            write!(f, ", exact text")?;
+        }
+        for (name, value) in &self.params {
+            write!(f, ", {}={}", name, value)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct Similarity {
     pub query: CacheQuery,
     pub score: f32,
+    pub reason: MatchReason,
 }
 
 #[async_trait]
 pub trait SimilarityCache: Send + Sync {
     async fn store(&self, query: CacheQuery) -> Result<()>;
-    async fn similarities(&self, query: CacheQuery) -> Result<Vec<Similarity>>;
+
+    async fn entries(&self) -> Result<Vec<CacheQuery>>;
+
+    /// Removes the entries matching `selector`, returning how many were removed.
+    async fn delete(&self, selector: &CacheSelector) -> Result<usize>;
+
+    /// Returns the cached entries that share the action and significant
+    /// parameters of `query`, ordered by descending score.
+    async fn similarities(
+        &self,
+        query: CacheQuery,
+        significant_params: &[String],
+    ) -> Result<Vec<Similarity>>;
 }
 
+#[derive(Default)]
 pub struct PassthroughSimilarityCache;
 
 impl PassthroughSimilarityCache {
@@ -36,7 +147,19 @@ impl SimilarityCache for PassthroughSimilarityCache {
         Ok(())
     }
 
-    async fn similarities(&self, _query: CacheQuery) -> Result<Vec<Similarity>> {
+    async fn entries(&self) -> Result<Vec<CacheQuery>> {
+        Ok(vec![])
+    }
+
+    async fn delete(&self, _selector: &CacheSelector) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn similarities(
+        &self,
+        _query: CacheQuery,
+        _significant_params: &[String],
+    ) -> Result<Vec<Similarity>> {
         Ok(vec![])
     }
 }
@@ -0,0 +1,95 @@
+// A stand-in for a central usage endpoint, which checks the signature of each
+// delivery and prints its reports. With `--fail` it answers 503, so that
+// batches pile up in the spool until it is restarted without it.
+//
+// PERPLEXITY_MCP_WEBHOOK_SECRET=secret cargo run -p usage_reporter --features webhook \
+//     --example webhook_receiver -- [address] [--fail]
+
+use std::env;
+
+use anyhow::{Context, Result, anyhow};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use usage_reporter::{SIGNATURE_HEADER, verify_signature};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut address = "127.0.0.1:8787".to_string();
+    let mut fail = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--fail" => fail = true,
+            _ => address = arg,
+        }
+    }
+    let secret = env::var("PERPLEXITY_MCP_WEBHOOK_SECRET")
+        .context("Set PERPLEXITY_MCP_WEBHOOK_SECRET to the secret the server signs with")?;
+
+    let listener = TcpListener::bind(&address).await?;
+    println!("Receiving usage at http://{}", address);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        if let Err(err) = receive(stream, secret.as_bytes(), fail).await {
+            eprintln!("Error receiving usage: {}", err);
+        }
+    }
+}
+
+async fn receive(mut stream: TcpStream, secret: &[u8], fail: bool) -> Result<()> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut content_length = 0;
+    let mut signature = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse()?;
+        } else if name.eq_ignore_ascii_case(SIGNATURE_HEADER) {
+            signature = Some(value.to_string());
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    let status = if fail {
+        println!("Refusing a delivery of {} bytes", body.len());
+        "503 Service Unavailable"
+    } else if !signature.is_some_and(|signature| verify_signature(secret, &body, &signature)) {
+        eprintln!("Rejecting a delivery with a missing or bad signature");
+        "401 Unauthorized"
+    } else {
+        let payload: serde_json::Value = serde_json::from_slice(&body)?;
+        let reports = payload["reports"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Delivery has no reports"))?;
+        for report in reports {
+            println!("{}", report);
+        }
+        "204 No Content"
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
@@ -0,0 +1,899 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::task::JoinHandle;
+
+use crate::{BudgetReservation, UsageEvent, UsageReport, UsageReporter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetPeriod {
+    Daily,
+    Monthly,
+}
+
+impl BudgetPeriod {
+    // Identifies the current period, in UTC
+    fn current(&self, now: DateTime<Utc>) -> String {
+        match self {
+            BudgetPeriod::Daily => now.format("%Y-%m-%d").to_string(),
+            BudgetPeriod::Monthly => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    fn resets(&self) -> &'static str {
+        match self {
+            BudgetPeriod::Daily => "at midnight UTC",
+            BudgetPeriod::Monthly => "at the start of next month (UTC)",
+        }
+    }
+}
+
+impl fmt::Display for BudgetPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetPeriod::Daily => f.write_str("daily"),
+            BudgetPeriod::Monthly => f.write_str("monthly"),
+        }
+    }
+}
+
+const UNNAMED_CLIENTS: &str = "*unnamed";
+
+// What a budget or rate limit counts: calls to one tool or all of them, from
+// one client, each client separately or all of them together
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct BudgetScope {
+    // Applies to every tool together when unset
+    pub tool: Option<String>,
+    // The client name sent in `initialize`, which clients are trusted to report
+    pub client: Option<String>,
+    // Gives each client an allowance of its own, rather than one they share
+    #[serde(default)]
+    pub per_client: bool,
+}
+
+impl BudgetScope {
+    fn applies_to(&self, tool: &str, client: Option<&str>) -> bool {
+        self.tool.as_deref().is_none_or(|limited| limited == tool)
+            && self
+                .client
+                .as_deref()
+                .is_none_or(|limited| client == Some(limited))
+    }
+
+    // Clients that did not send a name share an allowance
+    fn client<'a>(&'a self, client: Option<&'a str>) -> Option<&'a str> {
+        match &self.client {
+            Some(limited) => Some(limited),
+            None if self.per_client => Some(client.unwrap_or(UNNAMED_CLIENTS)),
+            None => None,
+        }
+    }
+
+    fn key(&self, client: Option<&str>) -> String {
+        let tool = self.tool.as_deref().unwrap_or("*");
+        match self.client(client) {
+            Some(client) => format!("{}:{}", tool, client),
+            None => tool.to_string(),
+        }
+    }
+
+    fn describe(&self, client: Option<&str>) -> String {
+        let mut description = String::new();
+        if let Some(tool) = &self.tool {
+            description.push_str(&format!(" for {}", tool));
+        }
+        match self.client(client) {
+            Some(UNNAMED_CLIENTS) => description.push_str(" of unnamed clients"),
+            Some(client) => description.push_str(&format!(" of client {}", client)),
+            None => {}
+        }
+        description
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct BudgetLimit {
+    pub period: BudgetPeriod,
+    #[serde(flatten)]
+    pub scope: BudgetScope,
+    pub tokens: Option<u64>,
+    pub dollars: Option<f64>,
+}
+
+impl BudgetLimit {
+    fn key(&self, client: Option<&str>) -> String {
+        format!("{}:{}", self.period, self.scope.key(client))
+    }
+
+    fn describe(&self, client: Option<&str>) -> String {
+        format!("{} budget{}", self.period, self.scope.describe(client))
+    }
+}
+
+// Caps the Perplexity calls made in any minute, so that a runaway agent is
+// slowed down well before it exhausts a budget
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RateLimit {
+    #[serde(flatten)]
+    pub scope: BudgetScope,
+    pub requests_per_minute: u32,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct BudgetConfig {
+    pub limits: Vec<BudgetLimit>,
+    pub rate_limits: Vec<RateLimit>,
+    // Fraction of a limit past which a warning is raised, once per period
+    pub warn_at: f64,
+    // Keeps the spend so far across restarts, shared by every server using the same file
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            limits: Vec::new(),
+            rate_limits: Vec::new(),
+            warn_at: 0.8,
+            state_path: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BudgetExceeded(pub String);
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
+struct Spend {
+    period: String,
+    tokens: u64,
+    dollars: f64,
+    warned_tokens: bool,
+    warned_dollars: bool,
+}
+
+impl Spend {
+    // Adds spend recorded elsewhere, unless it belongs to an earlier period
+    fn add(&mut self, other: &Spend) {
+        if other.period > self.period {
+            *self = Spend {
+                period: other.period.clone(),
+                ..Spend::default()
+            };
+        }
+        if other.period == self.period {
+            self.tokens += other.tokens;
+            self.dollars += other.dollars;
+            self.warned_tokens |= other.warned_tokens;
+            self.warned_dollars |= other.warned_dollars;
+        }
+    }
+}
+
+// Weight of the latest call in a tool's typical spend
+const TYPICAL_WEIGHT: f64 = 0.25;
+
+#[derive(Clone, Copy, Default)]
+struct Estimate {
+    tokens: f64,
+    dollars: f64,
+}
+
+#[derive(Default)]
+struct Reserved {
+    calls: usize,
+    tokens: f64,
+    dollars: f64,
+}
+
+#[derive(Default)]
+struct Ledger {
+    spend: HashMap<String, Spend>,
+    // The estimated spend of calls in flight, by limit key
+    reserved: HashMap<String, Reserved>,
+    // What a call to each tool spends, going by recent calls
+    typical: HashMap<String, Estimate>,
+    // Spend recorded since the state was last saved
+    unsaved: HashMap<String, Spend>,
+}
+
+impl Ledger {
+    // Takes the spend saved by every server, plus what this one has yet to save
+    fn catch_up(&mut self, saved: HashMap<String, Spend>) {
+        for (key, mut spend) in saved {
+            if let Some(unsaved) = self.unsaved.get(&key) {
+                spend.add(unsaved);
+            }
+            self.spend.insert(key, spend);
+        }
+    }
+
+    fn release(&mut self, keys: &[String], estimate: Estimate) {
+        for key in keys {
+            if let Some(reserved) = self.reserved.get_mut(key) {
+                reserved.calls -= 1;
+                reserved.tokens -= estimate.tokens;
+                reserved.dollars -= estimate.dollars;
+                if reserved.calls == 0 {
+                    self.reserved.remove(key);
+                }
+            }
+        }
+    }
+}
+
+fn read_state(path: &Path) -> Result<HashMap<String, Spend>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read budget state {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse budget state {}", path.display()))
+}
+
+// The spend kept at `state_path`, which each server adds its own to under a
+// file lock, so that servers for different clients can share budgets
+struct SharedState {
+    path: PathBuf,
+    lock_path: PathBuf,
+    // Held while saving, so that this process saves one batch at a time
+    saving: Mutex<()>,
+    // The version of the file last read or written, by modification time and size
+    seen: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl SharedState {
+    fn version(&self) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(&self.path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    fn lock_file(&self) -> Result<File> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_path)
+            .with_context(|| format!("Failed to open {}", self.lock_path.display()))
+    }
+
+    // Reads what other servers have spent, if they saved since this one last
+    // looked. A save in progress catches up by itself, so it is not waited for
+    fn refresh(&self, ledger: &Mutex<Ledger>) -> Result<()> {
+        let version = self.version();
+        let mut seen = self
+            .seen
+            .lock()
+            .map_err(|_| anyhow!("Budget state lock poisoned"))?;
+        if version.is_none() || *seen == version {
+            return Ok(());
+        }
+        let Ok(_saving) = self.saving.try_lock() else {
+            return Ok(());
+        };
+
+        let lock = self.lock_file()?;
+        lock.lock_shared()?;
+        let saved = read_state(&self.path)?;
+        *seen = self.version();
+        drop(lock);
+
+        ledger
+            .lock()
+            .map_err(|_| anyhow!("Budget lock poisoned"))?
+            .catch_up(saved);
+        Ok(())
+    }
+
+    fn save(&self, ledger: &Mutex<Ledger>) -> Result<()> {
+        let _saving = self
+            .saving
+            .lock()
+            .map_err(|_| anyhow!("Budget state lock poisoned"))?;
+        let unsaved = std::mem::take(
+            &mut ledger
+                .lock()
+                .map_err(|_| anyhow!("Budget lock poisoned"))?
+                .unsaved,
+        );
+        if unsaved.is_empty() {
+            return Ok(());
+        }
+
+        let saved = self.add(&unsaved);
+        let mut ledger = ledger.lock().map_err(|_| anyhow!("Budget lock poisoned"))?;
+        let saved = match saved {
+            Ok(saved) => saved,
+            Err(err) => {
+                for (key, spend) in unsaved {
+                    ledger.unsaved.entry(key).or_default().add(&spend);
+                }
+                return Err(err);
+            }
+        };
+
+        ledger.catch_up(saved);
+
+        Ok(())
+    }
+
+    fn add(&self, unsaved: &HashMap<String, Spend>) -> Result<HashMap<String, Spend>> {
+        let lock = self.lock_file()?;
+        lock.lock()?;
+
+        let mut saved = read_state(&self.path)?;
+        for (key, spend) in unsaved {
+            saved.entry(key.clone()).or_default().add(spend);
+        }
+
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&saved)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        if let Ok(mut seen) = self.seen.lock() {
+            *seen = self.version();
+        }
+
+        Ok(saved)
+    }
+}
+
+pub type BudgetWarningHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+// Tracks spend against the configured limits, forwarding every report to `inner`
+pub struct BudgetUsageReporter {
+    config: BudgetConfig,
+    inner: Option<Arc<dyn UsageReporter>>,
+    on_warning: Option<BudgetWarningHandler>,
+    ledger: Arc<Mutex<Ledger>>,
+    state: Option<Arc<SharedState>>,
+    // When each of the calls of the last minute was made, by rate limit key
+    calls: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl BudgetUsageReporter {
+    pub fn new(config: BudgetConfig, inner: Option<Arc<dyn UsageReporter>>) -> Result<Self> {
+        let spend = match &config.state_path {
+            Some(path) => read_state(path)?,
+            None => HashMap::new(),
+        };
+        let state = config.state_path.as_ref().map(|path| {
+            let mut lock_path = path.clone().into_os_string();
+            lock_path.push(".lock");
+            Arc::new(SharedState {
+                path: path.clone(),
+                lock_path: lock_path.into(),
+                saving: Mutex::new(()),
+                seen: Mutex::new(None),
+            })
+        });
+        if let Some(state) = &state
+            && let Ok(mut seen) = state.seen.lock()
+        {
+            *seen = state.version();
+        }
+
+        Ok(Self {
+            config,
+            inner,
+            on_warning: None,
+            ledger: Arc::new(Mutex::new(Ledger {
+                spend,
+                ..Ledger::default()
+            })),
+            state,
+            calls: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_warning_handler(mut self, on_warning: BudgetWarningHandler) -> Self {
+        self.on_warning = Some(on_warning);
+        self
+    }
+
+    // Saves the spend on a blocking thread, so that reports never wait on the file
+    fn save(&self) -> Option<JoinHandle<()>> {
+        let state = self.state.clone()?;
+        let ledger = self.ledger.clone();
+        let save = move || {
+            if let Err(err) = state.save(&ledger) {
+                log::error!("Failed to persist budget state: {}", err);
+            }
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => Some(runtime.spawn_blocking(save)),
+            Err(_) => {
+                save();
+                None
+            }
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        log::warn!("{}", message);
+        if let Some(on_warning) = &self.on_warning {
+            on_warning(message);
+        }
+    }
+
+    // Counts a call against every rate limit that applies, unless one of them
+    // is already reached
+    fn take_rate_limits(&self, tool: &str, client: Option<&str>) -> Result<()> {
+        let window = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut calls = self
+            .calls
+            .lock()
+            .map_err(|_| anyhow!("Rate limit lock poisoned"))?;
+
+        let mut keys = Vec::new();
+        for limit in self
+            .config
+            .rate_limits
+            .iter()
+            .filter(|l| l.scope.applies_to(tool, client))
+        {
+            let key = limit.scope.key(client);
+            let recent = calls.entry(key.clone()).or_default();
+            while recent
+                .front()
+                .is_some_and(|call| now.duration_since(*call) >= window)
+            {
+                recent.pop_front();
+            }
+
+            if recent.len() >= limit.requests_per_minute as usize {
+                let retry_in = recent
+                    .front()
+                    .map(|call| window.saturating_sub(now.duration_since(*call)))
+                    .unwrap_or(window);
+                return Err(BudgetExceeded(format!(
+                    "The rate limit of {} calls a minute{} is reached, so Perplexity was not called. Try again in {} seconds.",
+                    limit.requests_per_minute,
+                    limit.scope.describe(client),
+                    retry_in.as_secs().max(1)
+                ))
+                .into());
+            }
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        for key in keys {
+            calls.entry(key).or_default().push_back(now);
+        }
+
+        Ok(())
+    }
+}
+
+// The spend recorded for `limit` in the current period, reset when a new one starts
+fn current_spend<'a>(
+    spend: &'a mut HashMap<String, Spend>,
+    limit: &BudgetLimit,
+    client: Option<&str>,
+    now: DateTime<Utc>,
+) -> &'a mut Spend {
+    let period = limit.period.current(now);
+    let entry = spend.entry(limit.key(client)).or_default();
+    if entry.period != period {
+        *entry = Spend {
+            period,
+            ..Spend::default()
+        };
+    }
+    entry
+}
+
+#[async_trait]
+impl UsageReporter for BudgetUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        let tokens = report.usage.total_tokens;
+        let dollars = report.cost.unwrap_or(0.0);
+        let tool = report.tool.clone();
+        let client = report.client_name.clone();
+        let client = client.as_deref();
+        let now = report.timestamp;
+
+        let inner_result = match &self.inner {
+            Some(inner) => inner.report(report).await,
+            None => Ok(()),
+        };
+
+        if tokens == 0 && dollars == 0.0 {
+            return inner_result;
+        }
+
+        let mut warnings = Vec::new();
+        {
+            let mut ledger = self
+                .ledger
+                .lock()
+                .map_err(|_| anyhow!("Budget lock poisoned"))?;
+            let ledger = &mut *ledger;
+
+            let typical = ledger.typical.entry(tool.clone()).or_insert(Estimate {
+                tokens: tokens as f64,
+                dollars,
+            });
+            typical.tokens += (tokens as f64 - typical.tokens) * TYPICAL_WEIGHT;
+            typical.dollars += (dollars - typical.dollars) * TYPICAL_WEIGHT;
+
+            // Limits sharing a key share their spend, so count it once per key
+            let mut counted = Vec::new();
+            for limit in self
+                .config
+                .limits
+                .iter()
+                .filter(|l| l.scope.applies_to(&tool, client))
+            {
+                let key = limit.key(client);
+                let entry = current_spend(&mut ledger.spend, limit, client, now);
+                let mut added = Spend {
+                    period: entry.period.clone(),
+                    ..Spend::default()
+                };
+                if !counted.contains(&key) {
+                    entry.tokens += tokens;
+                    entry.dollars += dollars;
+                    added.tokens = tokens;
+                    added.dollars = dollars;
+                    counted.push(key.clone());
+                }
+
+                if let Some(cap) = limit.tokens
+                    && !entry.warned_tokens
+                    && entry.tokens as f64 >= cap as f64 * self.config.warn_at
+                {
+                    entry.warned_tokens = true;
+                    warnings.push(format!(
+                        "The {} is {:.0}% used ({} of {} tokens)",
+                        limit.describe(client),
+                        entry.tokens as f64 / cap as f64 * 100.0,
+                        entry.tokens,
+                        cap
+                    ));
+                }
+                if let Some(cap) = limit.dollars
+                    && !entry.warned_dollars
+                    && entry.dollars >= cap * self.config.warn_at
+                {
+                    entry.warned_dollars = true;
+                    warnings.push(format!(
+                        "The {} is {:.0}% used (${:.2} of ${:.2})",
+                        limit.describe(client),
+                        entry.dollars / cap * 100.0,
+                        entry.dollars,
+                        cap
+                    ));
+                }
+
+                added.warned_tokens = entry.warned_tokens;
+                added.warned_dollars = entry.warned_dollars;
+                ledger.unsaved.entry(key).or_default().add(&added);
+            }
+        }
+        self.save();
+
+        for warning in warnings {
+            self.warn(&warning);
+        }
+
+        inner_result
+    }
+
+    async fn flush(&self) -> Result<()> {
+        if let Some(saving) = self.save() {
+            saving.await?;
+        }
+
+        match &self.inner {
+            Some(inner) => inner.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    fn check_budget(&self, tool: &str, client: Option<&str>) -> Result<BudgetReservation> {
+        if let Some(state) = &self.state
+            && let Err(err) = state.refresh(&self.ledger)
+        {
+            log::error!("Failed to read budget state: {}", err);
+        }
+
+        let now = Utc::now();
+        let reservation = {
+            let mut ledger = self
+                .ledger
+                .lock()
+                .map_err(|_| anyhow!("Budget lock poisoned"))?;
+            let ledger = &mut *ledger;
+
+            let mut keys = Vec::new();
+            for limit in self
+                .config
+                .limits
+                .iter()
+                .filter(|l| l.scope.applies_to(tool, client))
+            {
+                let key = limit.key(client);
+                let reserved = ledger.reserved.get(&key);
+                let in_flight = if reserved.is_some() {
+                    " including calls in flight"
+                } else {
+                    ""
+                };
+                let entry = current_spend(&mut ledger.spend, limit, client, now);
+                let tokens = entry.tokens + reserved.map_or(0.0, |r| r.tokens).round() as u64;
+                let dollars = entry.dollars + reserved.map_or(0.0, |r| r.dollars);
+
+                let exhausted = match (limit.tokens, limit.dollars) {
+                    (Some(cap), _) if tokens >= cap => {
+                        Some(format!("{} of {} tokens used{}", tokens, cap, in_flight))
+                    }
+                    (_, Some(cap)) if dollars >= cap => {
+                        Some(format!("${:.2} of ${:.2} spent{}", dollars, cap, in_flight))
+                    }
+                    _ => None,
+                };
+
+                if let Some(exhausted) = exhausted {
+                    return Err(BudgetExceeded(format!(
+                        "The {} is exhausted ({}), so Perplexity was not called. It resets {}.",
+                        limit.describe(client),
+                        exhausted,
+                        limit.period.resets()
+                    ))
+                    .into());
+                }
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+
+            // Hold what the call will likely spend until it is reported, or fails
+            let estimate = ledger.typical.get(tool).copied().unwrap_or_default();
+            for key in &keys {
+                let reserved = ledger.reserved.entry(key.clone()).or_default();
+                reserved.calls += 1;
+                reserved.tokens += estimate.tokens;
+                reserved.dollars += estimate.dollars;
+            }
+            let ledger = self.ledger.clone();
+            BudgetReservation::new(move || {
+                if let Ok(mut ledger) = ledger.lock() {
+                    ledger.release(&keys, estimate);
+                }
+            })
+        };
+
+        let reservation = match &self.inner {
+            Some(inner) => reservation.join(inner.check_budget(tool, client)?),
+            None => reservation,
+        };
+        self.take_rate_limits(tool, client)?;
+
+        Ok(reservation)
+    }
+
+    fn record(&self, event: UsageEvent<'_>) {
+        if let Some(inner) = &self.inner {
+            inner.record(event);
+        }
+    }
+
+    fn name(&self) -> &str {
+        "budget"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::{CacheStatus, Usage};
+
+    fn report(
+        tool: &str,
+        client: Option<&str>,
+        tokens: u64,
+        timestamp: DateTime<Utc>,
+    ) -> UsageReport {
+        UsageReport {
+            timestamp,
+            tool: tool.into(),
+            request_id: None,
+            client_name: client.map(Into::into),
+            model: "sonar".into(),
+            usage: Usage {
+                total_tokens: tokens,
+                ..Usage::default()
+            },
+            cache: CacheStatus::Miss,
+            latency: Duration::ZERO,
+            cost: None,
+            saved_cost: None,
+            http_attempts: 1,
+        }
+    }
+
+    fn reporter(limits: &str) -> Result<BudgetUsageReporter> {
+        shared_reporter(limits, None)
+    }
+
+    fn shared_reporter(limits: &str, state_path: Option<PathBuf>) -> Result<BudgetUsageReporter> {
+        let limits = serde_json::from_str(limits)?;
+        BudgetUsageReporter::new(
+            BudgetConfig {
+                limits,
+                state_path,
+                ..BudgetConfig::default()
+            },
+            None,
+        )
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "usage_reporter-budget-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("budget.json")
+    }
+
+    fn scope(scope: &str) -> Result<BudgetScope> {
+        Ok(serde_json::from_str(scope)?)
+    }
+
+    #[test]
+    fn scopes_key_spend_by_tool_and_client() -> Result<()> {
+        assert_eq!(scope("{}")?.key(Some("zed")), "*");
+        assert_eq!(scope(r#"{ "tool": "search" }"#)?.key(Some("zed")), "search");
+        assert_eq!(scope(r#"{ "client": "zed" }"#)?.key(None), "*:zed");
+        assert_eq!(
+            scope(r#"{ "per_client": true }"#)?.key(Some("zed")),
+            "*:zed"
+        );
+        assert_eq!(
+            scope(r#"{ "per_client": true }"#)?.key(None),
+            format!("*:{}", UNNAMED_CLIENTS)
+        );
+
+        let limited = scope(r#"{ "tool": "search", "client": "zed" }"#)?;
+        assert!(limited.applies_to("search", Some("zed")));
+        assert!(!limited.applies_to("search", Some("cursor")));
+        assert!(!limited.applies_to("search", None));
+        assert!(!limited.applies_to("find_apis", Some("zed")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn each_client_gets_its_own_allowance() -> Result<()> {
+        let budget = reporter(r#"[{ "period": "daily", "per_client": true, "tokens": 100 }]"#)?;
+        budget
+            .report(report("search", Some("zed"), 150, Utc::now()))
+            .await?;
+
+        let err = budget
+            .check_budget("search", Some("zed"))
+            .err()
+            .map(|err| err.to_string());
+        assert!(err.is_some_and(|err| err.contains("of client zed")));
+        assert!(budget.check_budget("search", Some("cursor")).is_ok());
+        assert!(budget.check_budget("search", None).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_sharing_a_state_path_add_up_their_spend() -> Result<()> {
+        let path = state_path("shared");
+        let limits = r#"[{ "period": "daily", "tokens": 100 }]"#;
+
+        let first = shared_reporter(limits, Some(path.clone()))?;
+        let second = shared_reporter(limits, Some(path.clone()))?;
+        first.report(report("search", None, 60, Utc::now())).await?;
+        first.flush().await?;
+        second
+            .report(report("search", None, 50, Utc::now()))
+            .await?;
+        second.flush().await?;
+
+        assert!(second.check_budget("search", None).is_err());
+        let restarted = shared_reporter(limits, Some(path))?;
+        let err = restarted
+            .check_budget("search", None)
+            .err()
+            .map(|err| err.to_string());
+        assert!(err.is_some_and(|err| err.contains("110 of 100 tokens used")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_servers_see_what_others_spent() -> Result<()> {
+        let path = state_path("idle");
+        let limits = r#"[{ "period": "daily", "tokens": 100 }]"#;
+
+        let idle = shared_reporter(limits, Some(path.clone()))?;
+        let busy = shared_reporter(limits, Some(path))?;
+        assert!(idle.check_budget("search", None).is_ok());
+
+        busy.report(report("search", None, 120, Utc::now())).await?;
+        busy.flush().await?;
+
+        let err = idle
+            .check_budget("search", None)
+            .err()
+            .map(|err| err.to_string());
+        assert!(err.is_some_and(|err| err.contains("120 of 100 tokens used")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spend_resets_with_the_period() -> Result<()> {
+        let budget = reporter(r#"[{ "period": "daily", "tokens": 100 }]"#)?;
+
+        let yesterday = Utc::now() - TimeDelta::days(1);
+        budget
+            .report(report("search", None, 150, yesterday))
+            .await?;
+        assert!(budget.check_budget("search", None).is_ok());
+
+        budget
+            .report(report("search", None, 150, Utc::now()))
+            .await?;
+        let err = budget
+            .check_budget("search", None)
+            .err()
+            .map(|err| err.to_string());
+        assert!(err.is_some_and(|err| err.contains("150 of 100 tokens used")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calls_in_flight_hold_their_estimated_spend() -> Result<()> {
+        let budget = reporter(r#"[{ "period": "daily", "tokens": 100 }]"#)?;
+        budget
+            .report(report("search", None, 60, Utc::now()))
+            .await?;
+
+        let first = budget.check_budget("search", None)?;
+        assert!(budget.check_budget("search", None).is_err());
+
+        drop(first);
+        let second = budget.check_budget("search", None)?;
+        budget
+            .report(report("search", None, 60, Utc::now()))
+            .await?;
+        drop(second);
+        assert!(budget.check_budget("search", None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::MissedTickBehavior,
+};
+
+use crate::{BudgetReservation, UsageEvent, UsageReport, UsageReporter};
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct BufferedUsageReporterConfig {
+    // Reports arriving while this many wait to be written are dropped
+    pub capacity: usize,
+    pub batch_size: usize,
+    // Writes a partial batch once it has waited this long
+    pub flush_interval_ms: u64,
+}
+
+impl Default for BufferedUsageReporterConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            batch_size: 64,
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+enum Message {
+    Report(Box<UsageReport>),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+// Queues reports for a background task that writes them to `inner` in batches,
+// so that tool calls never wait on a file, database or network
+pub struct BufferedUsageReporter {
+    inner: Arc<dyn UsageReporter>,
+    sender: mpsc::Sender<Message>,
+    dropped: AtomicU64,
+}
+
+impl BufferedUsageReporter {
+    pub fn new(config: BufferedUsageReporterConfig, inner: Arc<dyn UsageReporter>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+        tokio::spawn(write_batches(config, inner.clone(), receiver));
+
+        Self {
+            inner,
+            sender,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // Reports dropped so far because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn write_batches(
+    config: BufferedUsageReporterConfig,
+    inner: Arc<dyn UsageReporter>,
+    mut receiver: mpsc::Receiver<Message>,
+) {
+    let batch_size = config.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut interval =
+        tokio::time::interval(Duration::from_millis(config.flush_interval_ms.max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Some(Message::Report(report)) => {
+                    batch.push(*report);
+                    if batch.len() >= batch_size {
+                        write_batch(&inner, &mut batch).await;
+                    }
+                }
+                Some(Message::Flush(done)) => {
+                    write_batch(&inner, &mut batch).await;
+                    let _ = done.send(inner.flush().await);
+                }
+                None => break,
+            },
+            _ = interval.tick() => write_batch(&inner, &mut batch).await,
+        }
+    }
+
+    write_batch(&inner, &mut batch).await;
+}
+
+async fn write_batch(inner: &Arc<dyn UsageReporter>, batch: &mut Vec<UsageReport>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let reports = std::mem::take(batch);
+    let count = reports.len();
+    if let Err(err) = inner.report_batch(reports).await {
+        log::error!("Failed to write {} usage reports: {}", count, err);
+    }
+}
+
+#[async_trait]
+impl UsageReporter for BufferedUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        match self.sender.try_send(Message::Report(Box::new(report))) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // Logged less and less often, so that a stalled writer does not flood the log
+                if dropped.is_power_of_two() {
+                    log::warn!(
+                        "Usage report buffer is full, {} reports dropped so far",
+                        dropped
+                    );
+                }
+                Err(anyhow!(
+                    "Usage report buffer is full, {} reports dropped so far",
+                    dropped
+                ))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(anyhow!("Usage report writer has stopped"))
+            }
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let (done, flushed) = oneshot::channel();
+        self.sender
+            .send(Message::Flush(done))
+            .await
+            .map_err(|_| anyhow!("Usage report writer has stopped"))?;
+
+        flushed
+            .await
+            .map_err(|_| anyhow!("Usage report writer has stopped"))?
+    }
+
+    fn check_budget(&self, tool: &str, client: Option<&str>) -> Result<BudgetReservation> {
+        self.inner.check_budget(tool, client)
+    }
+
+    // Events are meant to be cheap, so they skip the queue
+    fn record(&self, event: UsageEvent<'_>) {
+        self.inner.record(event);
+    }
+
+    fn name(&self) -> &str {
+        "buffered"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{Mutex, Notify, Semaphore};
+
+    use super::*;
+    use crate::test_report;
+
+    // Writes nothing until let through, noting the size of each batch and flush
+    struct Recorder {
+        batches: Mutex<Vec<usize>>,
+        flushes: Mutex<u32>,
+        started: Notify,
+        gate: Semaphore,
+    }
+
+    impl Recorder {
+        fn new(open: bool) -> Arc<Self> {
+            Arc::new(Self {
+                batches: Mutex::new(Vec::new()),
+                flushes: Mutex::new(0),
+                started: Notify::new(),
+                gate: Semaphore::new(usize::from(open)),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl UsageReporter for Recorder {
+        async fn report(&self, report: UsageReport) -> Result<()> {
+            self.report_batch(vec![report]).await
+        }
+
+        async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+            self.started.notify_one();
+            let _permit = self.gate.acquire().await?;
+            self.batches.lock().await.push(reports.len());
+            Ok(())
+        }
+
+        async fn flush(&self) -> Result<()> {
+            *self.flushes.lock().await += 1;
+            Ok(())
+        }
+    }
+
+    fn config(capacity: usize, batch_size: usize) -> BufferedUsageReporterConfig {
+        BufferedUsageReporterConfig {
+            capacity,
+            batch_size,
+            // Long enough that only full batches and flushes write
+            flush_interval_ms: 60_000,
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_are_written_in_batches() -> Result<()> {
+        let recorder = Recorder::new(true);
+        let buffered = BufferedUsageReporter::new(config(16, 3), recorder.clone());
+
+        for _ in 0..5 {
+            buffered.report(test_report("search")).await?;
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(*recorder.batches.lock().await, vec![3]);
+
+        // The rest are written on flush, which reaches the inner reporter too
+        buffered.flush().await?;
+        assert_eq!(*recorder.batches.lock().await, vec![3, 2]);
+        assert_eq!(*recorder.flushes.lock().await, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_are_dropped_and_counted_when_the_buffer_is_full() -> Result<()> {
+        let recorder = Recorder::new(false);
+        let buffered = BufferedUsageReporter::new(config(2, 1), recorder.clone());
+
+        // The writer holds the first report while two more fill the buffer
+        buffered.report(test_report("search")).await?;
+        recorder.started.notified().await;
+        buffered.report(test_report("find_apis")).await?;
+        buffered.report(test_report("get_documentation")).await?;
+
+        assert!(buffered.report(test_report("search")).await.is_err());
+        assert!(buffered.report(test_report("search")).await.is_err());
+        assert_eq!(buffered.dropped(), 2);
+
+        // What was queued is still written
+        recorder.gate.add_permits(1);
+        buffered.flush().await?;
+        assert_eq!(*recorder.batches.lock().await, vec![1, 1, 1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use tokio::task::JoinHandle;
+
+use crate::{BudgetReservation, UsageEvent, UsageReport, UsageReporter};
+
+// Sends every report to each of `reporters` concurrently, so that one failing,
+// or even panicking, does not keep the others from recording it
+pub struct CompositeUsageReporter {
+    reporters: Vec<Arc<dyn UsageReporter>>,
+}
+
+impl CompositeUsageReporter {
+    pub fn new(reporters: Vec<Arc<dyn UsageReporter>>) -> Self {
+        Self { reporters }
+    }
+
+    async fn join(&self, action: &str, tasks: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+        let mut failures = 0;
+        for (reporter, task) in self.reporters.iter().zip(tasks) {
+            let err = match task.await {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => err,
+                Err(err) => anyhow!(err),
+            };
+            log::error!(
+                "Usage reporter {} failed to {}: {}",
+                reporter.name(),
+                action,
+                err
+            );
+            failures += 1;
+        }
+
+        match failures {
+            0 => Ok(()),
+            _ => Err(anyhow!(
+                "{} of {} usage reporters failed to {}",
+                failures,
+                self.reporters.len(),
+                action
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl UsageReporter for CompositeUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        self.report_batch(vec![report]).await
+    }
+
+    async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+        let tasks = self
+            .reporters
+            .iter()
+            .map(|reporter| {
+                let reporter = reporter.clone();
+                let reports = reports.clone();
+                tokio::spawn(async move { reporter.report_batch(reports).await })
+            })
+            .collect();
+
+        self.join("report usage", tasks).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let tasks = self
+            .reporters
+            .iter()
+            .map(|reporter| {
+                let reporter = reporter.clone();
+                tokio::spawn(async move { reporter.flush().await })
+            })
+            .collect();
+
+        self.join("flush", tasks).await
+    }
+
+    fn check_budget(&self, tool: &str, client: Option<&str>) -> Result<BudgetReservation> {
+        self.reporters
+            .iter()
+            .try_fold(BudgetReservation::default(), |reservation, reporter| {
+                Ok(reservation.join(reporter.check_budget(tool, client)?))
+            })
+    }
+
+    fn record(&self, event: UsageEvent<'_>) {
+        for reporter in &self.reporters {
+            reporter.record(event);
+        }
+    }
+
+    fn name(&self) -> &str {
+        "composite"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::test_report;
+
+    enum Behaviour {
+        Record,
+        Fail,
+        Panic,
+    }
+
+    struct Reporter {
+        behaviour: Behaviour,
+        tools: Mutex<Vec<String>>,
+        flushes: Mutex<u32>,
+    }
+
+    impl Reporter {
+        fn new(behaviour: Behaviour) -> Arc<Self> {
+            Arc::new(Self {
+                behaviour,
+                tools: Mutex::new(Vec::new()),
+                flushes: Mutex::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl UsageReporter for Reporter {
+        async fn report(&self, report: UsageReport) -> Result<()> {
+            self.report_batch(vec![report]).await
+        }
+
+        async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+            match self.behaviour {
+                Behaviour::Record => {}
+                Behaviour::Fail => return Err(anyhow!("Disk full")),
+                Behaviour::Panic => panic!("Reporter bug"),
+            }
+
+            let mut tools = self.tools.lock().await;
+            tools.extend(reports.into_iter().map(|report| report.tool));
+            Ok(())
+        }
+
+        async fn flush(&self) -> Result<()> {
+            match self.behaviour {
+                Behaviour::Record => {}
+                Behaviour::Fail => return Err(anyhow!("Disk full")),
+                Behaviour::Panic => panic!("Reporter bug"),
+            }
+
+            *self.flushes.lock().await += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_reporters_do_not_stop_the_others() -> Result<()> {
+        let (first, last) = (
+            Reporter::new(Behaviour::Record),
+            Reporter::new(Behaviour::Record),
+        );
+        let composite = CompositeUsageReporter::new(vec![
+            first.clone(),
+            Reporter::new(Behaviour::Fail),
+            Reporter::new(Behaviour::Panic),
+            last.clone(),
+        ]);
+
+        let err = composite.report(test_report("search")).await;
+        assert_eq!(
+            err.map_err(|err| err.to_string()),
+            Err("2 of 4 usage reporters failed to report usage".to_string())
+        );
+        assert_eq!(*first.tools.lock().await, vec!["search".to_string()]);
+        assert_eq!(*last.tools.lock().await, vec!["search".to_string()]);
+
+        assert!(composite.flush().await.is_err());
+        assert_eq!(*first.flushes.lock().await, 1);
+        assert_eq!(*last.flushes.lock().await, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_reach_every_reporter_whole() -> Result<()> {
+        let (first, second) = (
+            Reporter::new(Behaviour::Record),
+            Reporter::new(Behaviour::Record),
+        );
+        let composite = CompositeUsageReporter::new(vec![first.clone(), second.clone()]);
+
+        composite
+            .report_batch(vec![test_report("search"), test_report("find_apis")])
+            .await?;
+
+        for reporter in [first, second] {
+            assert_eq!(
+                *reporter.tools.lock().await,
+                vec!["search".to_string(), "find_apis".to_string()]
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{SearchContextSize, UsageReport, UsageReporter};
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct JsonlUsageReporterConfig {
+    pub path: PathBuf,
+    // The file is rotated once it would grow past `max_bytes`, keeping
+    // `max_files` older ones as `path.1`, `path.2` and so on
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+    // Also rotates the file on the first write of each UTC day
+    #[serde(default)]
+    pub rotate_daily: bool,
+}
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+// How a report is written out, one per line here and batched by the webhook
+#[derive(serde::Serialize)]
+pub(crate) struct UsageLine<'a> {
+    timestamp: DateTime<Utc>,
+    tool: &'a str,
+    request_id: Option<&'a str>,
+    client_name: Option<&'a str>,
+    model: &'a str,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+    citation_tokens: u64,
+    reasoning_tokens: u64,
+    num_search_queries: u64,
+    search_context_size: Option<SearchContextSize>,
+    cache: &'static str,
+    cache_hit: bool,
+    cache_score: Option<f32>,
+    saved_tokens: Option<u64>,
+    latency_ms: u64,
+    http_attempts: u32,
+    cost_usd: Option<f64>,
+    saved_cost_usd: Option<f64>,
+}
+
+// Appends one JSON line per report to a file
+pub struct JsonlUsageReporter {
+    config: Arc<JsonlUsageReporterConfig>,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl JsonlUsageReporter {
+    pub fn new(config: JsonlUsageReporterConfig) -> Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        Ok(Self {
+            config: Arc::new(config),
+            write_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+}
+
+fn rotated_path(config: &JsonlUsageReporterConfig, index: usize) -> PathBuf {
+    let mut path = config.path.clone().into_os_string();
+    path.push(format!(".{}", index));
+    path.into()
+}
+
+fn rotate(config: &JsonlUsageReporterConfig) -> Result<()> {
+    if config.max_files == 0 {
+        return Ok(fs::remove_file(&config.path)?);
+    }
+
+    for index in (1..config.max_files).rev() {
+        let from = rotated_path(config, index);
+        if from.exists() {
+            fs::rename(&from, rotated_path(config, index + 1))?;
+        }
+    }
+    fs::rename(&config.path, rotated_path(config, 1))?;
+
+    Ok(())
+}
+
+fn append(config: &JsonlUsageReporterConfig, lines: &[u8]) -> Result<()> {
+    let metadata = fs::metadata(&config.path).ok();
+    let size = metadata
+        .as_ref()
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let new_day = config.rotate_daily
+        && metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .is_some_and(|modified| {
+                DateTime::<Utc>::from(modified).date_naive() < Utc::now().date_naive()
+            });
+    if size > 0
+        && (new_day || size + lines.len() as u64 > config.max_bytes)
+        && let Err(err) = rotate(config)
+    {
+        log::error!(
+            "Failed to rotate usage log {}: {}",
+            config.path.display(),
+            err
+        );
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.path)?
+        .write_all(lines)?;
+
+    Ok(())
+}
+
+impl<'a> From<&'a UsageReport> for UsageLine<'a> {
+    fn from(report: &'a UsageReport) -> Self {
+        let saved = report.cache.saved();
+        UsageLine {
+            timestamp: report.timestamp,
+            tool: &report.tool,
+            request_id: report.request_id.as_deref(),
+            client_name: report.client_name.as_deref(),
+            model: &report.model,
+            prompt_tokens: report.usage.prompt_tokens,
+            completion_tokens: report.usage.completion_tokens,
+            total_tokens: report.usage.total_tokens,
+            citation_tokens: report.usage.citation_tokens,
+            reasoning_tokens: report.usage.reasoning_tokens,
+            num_search_queries: report.usage.num_search_queries,
+            search_context_size: report.usage.search_context_size,
+            cache: report.cache.name(),
+            cache_hit: saved.is_some(),
+            cache_score: report.cache.score(),
+            saved_tokens: saved.map(|saved| saved.total_tokens),
+            latency_ms: report.latency.as_millis() as u64,
+            http_attempts: report.http_attempts,
+            cost_usd: report.cost,
+            saved_cost_usd: report.saved_cost,
+        }
+    }
+}
+
+#[async_trait]
+impl UsageReporter for JsonlUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        self.report_batch(vec![report]).await
+    }
+
+    async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+        let mut lines = Vec::new();
+        for report in &reports {
+            serde_json::to_writer(&mut lines, &UsageLine::from(report))?;
+            lines.push(b'\n');
+        }
+
+        let config = self.config.clone();
+        let write_lock = self.write_lock.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = write_lock
+                .lock()
+                .map_err(|_| anyhow!("Usage reporter lock poisoned"))?;
+            append(&config, &lines)
+        })
+        .await?
+    }
+
+    fn name(&self) -> &str {
+        "jsonl"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_report;
+
+    fn reporter(name: &str, max_bytes: u64, rotate_daily: bool) -> Result<JsonlUsageReporter> {
+        let dir = std::env::temp_dir().join(format!(
+            "usage_reporter-jsonl-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+
+        JsonlUsageReporter::new(JsonlUsageReporterConfig {
+            path: dir.join("usage.jsonl"),
+            max_bytes,
+            max_files: 2,
+            rotate_daily,
+        })
+    }
+
+    // The tools of the lines in `path`, which must each be a whole report
+    fn tools(path: &Path) -> Result<Vec<String>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        contents
+            .lines()
+            .map(|line| {
+                let report: Value = serde_json::from_str(line)?;
+                report["tool"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("Line without a tool: {}", line))
+            })
+            .collect()
+    }
+
+    fn files(reporter: &JsonlUsageReporter) -> Result<Vec<Vec<String>>> {
+        [
+            reporter.path().to_path_buf(),
+            rotated_path(&reporter.config, 1),
+            rotated_path(&reporter.config, 2),
+            rotated_path(&reporter.config, 3),
+        ]
+        .iter()
+        .map(|path| tools(path))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn files_roll_over_past_max_bytes() -> Result<()> {
+        // Reports with the same timestamp and tools of the same length make
+        // lines of the same length
+        let report = test_report("a");
+        let line = serde_json::to_vec(&UsageLine::from(&report))?.len() as u64 + 1;
+        let reporter = reporter("size", 2 * line, false)?;
+        let named = |tool: &str| UsageReport {
+            tool: tool.into(),
+            ..report.clone()
+        };
+
+        reporter.report(named("a")).await?;
+        reporter.report(named("b")).await?;
+        // Exactly at the threshold, so nothing has rolled over yet
+        assert_eq!(fs::metadata(reporter.path())?.len(), 2 * line);
+        assert_eq!(files(&reporter)?[1], Vec::<String>::new());
+
+        reporter.report(named("c")).await?;
+        assert_eq!(
+            files(&reporter)?,
+            vec![vec!["c"], vec!["a", "b"], vec![], vec![]]
+        );
+
+        // A batch stays whole, in a file of its own when it does not fit
+        reporter
+            .report_batch(vec![named("d"), named("e"), named("f")])
+            .await?;
+        assert_eq!(
+            files(&reporter)?,
+            vec![vec!["d", "e", "f"], vec!["c"], vec!["a", "b"], vec![]]
+        );
+
+        // Past `max_files`, the oldest file goes
+        reporter.report(named("g")).await?;
+        assert_eq!(
+            files(&reporter)?,
+            vec![vec!["g"], vec!["d", "e", "f"], vec!["c"], vec![]]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_roll_over_on_a_new_day() -> Result<()> {
+        let reporter = reporter("daily", default_max_bytes(), true)?;
+
+        reporter.report(test_report("a")).await?;
+        reporter.report(test_report("b")).await?;
+        assert_eq!(files(&reporter)?[0], vec!["a", "b"]);
+
+        // As if the last line had been written yesterday
+        File::options()
+            .write(true)
+            .open(reporter.path())?
+            .set_modified(SystemTime::now() - Duration::from_secs(24 * 60 * 60))?;
+
+        reporter.report(test_report("c")).await?;
+        reporter.report(test_report("d")).await?;
+        assert_eq!(
+            files(&reporter)?,
+            vec![vec!["c", "d"], vec!["a", "b"], vec![], vec![]]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use opentelemetry::{
+    KeyValue,
+    metrics::{Counter, Histogram, Meter},
+};
+
+use crate::{UsageReport, UsageReporter};
+
+// Turns reports into OpenTelemetry counters and histograms, exported by
+// whichever meter provider `meter` comes from
+pub struct OtelUsageReporter {
+    calls: Counter<u64>,
+    duration: Histogram<f64>,
+    tokens: Counter<u64>,
+    saved_tokens: Counter<u64>,
+    search_queries: Counter<u64>,
+    cost: Counter<f64>,
+    saved_cost: Counter<f64>,
+    http_attempts: Counter<u64>,
+}
+
+impl OtelUsageReporter {
+    pub fn new(meter: &Meter) -> Self {
+        Self {
+            calls: meter
+                .u64_counter("perplexity_mcp.tool.calls")
+                .with_description("Tool calls that reached Perplexity or the cache")
+                .build(),
+            duration: meter
+                .f64_histogram("perplexity_mcp.tool.duration")
+                .with_description("Time to answer a tool call")
+                .with_unit("s")
+                .build(),
+            tokens: meter
+                .u64_counter("perplexity_mcp.tokens")
+                .with_description("Tokens spent, by type")
+                .build(),
+            saved_tokens: meter
+                .u64_counter("perplexity_mcp.tokens.saved")
+                .with_description("Tokens the cache avoided spending")
+                .build(),
+            search_queries: meter
+                .u64_counter("perplexity_mcp.search_queries")
+                .with_description("Searches run by Perplexity")
+                .build(),
+            cost: meter
+                .f64_counter("perplexity_mcp.cost")
+                .with_description("Estimated spend")
+                .with_unit("USD")
+                .build(),
+            saved_cost: meter
+                .f64_counter("perplexity_mcp.cost.saved")
+                .with_description("Estimated spend the cache avoided")
+                .with_unit("USD")
+                .build(),
+            http_attempts: meter
+                .u64_counter("perplexity_mcp.http.attempts")
+                .with_description("HTTP requests sent to Perplexity")
+                .build(),
+        }
+    }
+}
+
+#[async_trait]
+impl UsageReporter for OtelUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        let attributes = [
+            KeyValue::new("tool", report.tool.clone()),
+            KeyValue::new("model", report.model.clone()),
+        ];
+        let with = |key: &'static str, value: &'static str| {
+            let mut attributes = attributes.to_vec();
+            attributes.push(KeyValue::new(key, value));
+            attributes
+        };
+
+        let cache = report.cache.name();
+        self.calls.add(1, &with("cache", cache));
+        self.duration
+            .record(report.latency.as_secs_f64(), &with("cache", cache));
+
+        let usage = report.usage;
+        for (kind, tokens) in [
+            ("prompt", usage.prompt_tokens),
+            ("completion", usage.completion_tokens),
+            ("citation", usage.citation_tokens),
+            ("reasoning", usage.reasoning_tokens),
+        ] {
+            if tokens > 0 {
+                self.tokens.add(tokens, &with("type", kind));
+            }
+        }
+        if usage.num_search_queries > 0 {
+            self.search_queries
+                .add(usage.num_search_queries, &attributes);
+        }
+        if let Some(saved) = report.cache.saved() {
+            self.saved_tokens.add(saved.total_tokens, &attributes);
+        }
+
+        if let Some(cost) = report.cost {
+            self.cost.add(cost, &attributes);
+        }
+        if let Some(saved_cost) = report.saved_cost {
+            self.saved_cost.add(saved_cost, &attributes);
+        }
+        if report.http_attempts > 0 {
+            self.http_attempts
+                .add(u64::from(report.http_attempts), &attributes);
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "otel"
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::{SearchContextSize, Usage};
+
+// Prices in dollars, per million tokens unless stated otherwise
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    pub reasoning: f64,
+    pub citation: f64,
+    // Per thousand search queries
+    pub search_queries: f64,
+    // Per thousand requests
+    pub request_fees: RequestFees,
+}
+
+// Per thousand requests, by search context size
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RequestFees {
+    pub low: f64,
+    pub medium: f64,
+    pub high: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let per_million = |tokens: u64, price: f64| tokens as f64 * price / 1_000_000.0;
+        let per_thousand = |count: u64, price: f64| count as f64 * price / 1_000.0;
+
+        // Perplexity searches with a low context unless told otherwise
+        let request_fee = match usage.search_context_size {
+            Some(SearchContextSize::High) => self.request_fees.high,
+            Some(SearchContextSize::Medium) => self.request_fees.medium,
+            Some(SearchContextSize::Low) | None => self.request_fees.low,
+        };
+
+        per_million(usage.prompt_tokens, self.input)
+            + per_million(usage.completion_tokens, self.output)
+            + per_million(usage.reasoning_tokens, self.reasoning)
+            + per_million(usage.citation_tokens, self.citation)
+            + per_thousand(usage.num_search_queries, self.search_queries)
+            + request_fee / 1_000.0
+    }
+}
+
+pub struct PricingTable {
+    models: HashMap<String, ModelPrice>,
+}
+
+impl Default for PricingTable {
+    fn default() -> Self {
+        let token_prices = |input, output, low, medium, high| ModelPrice {
+            input,
+            output,
+            request_fees: RequestFees { low, medium, high },
+            ..ModelPrice::default()
+        };
+
+        let models = HashMap::from([
+            ("sonar".into(), token_prices(1.0, 1.0, 5.0, 8.0, 12.0)),
+            ("sonar-pro".into(), token_prices(3.0, 15.0, 6.0, 10.0, 14.0)),
+            (
+                "sonar-reasoning".into(),
+                token_prices(1.0, 5.0, 5.0, 8.0, 12.0),
+            ),
+            (
+                "sonar-reasoning-pro".into(),
+                token_prices(2.0, 8.0, 6.0, 10.0, 14.0),
+            ),
+            (
+                "sonar-deep-research".into(),
+                ModelPrice {
+                    input: 2.0,
+                    output: 8.0,
+                    reasoning: 3.0,
+                    citation: 2.0,
+                    search_queries: 5.0,
+                    request_fees: RequestFees::default(),
+                },
+            ),
+            ("r1-1776".into(), token_prices(2.0, 8.0, 0.0, 0.0, 0.0)),
+        ]);
+
+        Self { models }
+    }
+}
+
+impl PricingTable {
+    // Replaces the built-in prices of each model in `overrides`, or adds them
+    pub fn with_overrides(mut self, overrides: HashMap<String, ModelPrice>) -> Self {
+        self.models.extend(overrides);
+        self
+    }
+
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.models.get(model)
+    }
+
+    // The estimated cost in dollars, or `None` for models without a price
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        self.price(model).map(|price| price.cost(usage))
+    }
+}
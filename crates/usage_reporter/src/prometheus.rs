@@ -0,0 +1,216 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+
+use crate::{UsageEvent, UsageReport, UsageReporter};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ToolLabels {
+    tool: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HttpLabels {
+    tool: String,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CacheLabels {
+    tool: String,
+    result: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TokenLabels {
+    model: String,
+    kind: String,
+}
+
+type HistogramFamily = Family<ToolLabels, Histogram, fn() -> Histogram>;
+
+// Keeps counters, gauges and histograms in memory for a Prometheus scrape
+pub struct PrometheusUsageReporter {
+    registry: Registry,
+    tool_calls: Family<ToolLabels, Counter>,
+    in_flight: Family<ToolLabels, Gauge>,
+    duration: HistogramFamily,
+    http_responses: Family<HttpLabels, Counter>,
+    http_retries: Family<ToolLabels, Counter>,
+    cache_results: Family<CacheLabels, Counter>,
+    cache_scores: HistogramFamily,
+    tokens: Family<TokenLabels, Counter>,
+}
+
+impl Default for PrometheusUsageReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrometheusUsageReporter {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("perplexity_mcp");
+
+        let tool_calls = Family::<ToolLabels, Counter>::default();
+        registry.register("tool_calls", "Tool calls, by tool", tool_calls.clone());
+
+        let in_flight = Family::<ToolLabels, Gauge>::default();
+        registry.register(
+            "in_flight_requests",
+            "Tool calls being answered",
+            in_flight.clone(),
+        );
+
+        // From 50ms to about seven minutes, which deep research can take
+        let duration: HistogramFamily =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.05, 2.0, 14)));
+        registry.register(
+            "tool_duration_seconds",
+            "Time to answer a successful tool call",
+            duration.clone(),
+        );
+
+        let http_responses = Family::<HttpLabels, Counter>::default();
+        registry.register(
+            "http_responses",
+            "Responses from the Perplexity API, by status",
+            http_responses.clone(),
+        );
+
+        let http_retries = Family::<ToolLabels, Counter>::default();
+        registry.register(
+            "http_retries",
+            "HTTP requests sent to Perplexity beyond the first of a call",
+            http_retries.clone(),
+        );
+
+        let cache_results = Family::<CacheLabels, Counter>::default();
+        registry.register(
+            "cache_results",
+            "Answered calls, by whether the cache answered them",
+            cache_results.clone(),
+        );
+
+        // Most cache thresholds sit well above 0.5, so the buckets are finer near 1
+        let cache_scores: HistogramFamily = Family::new_with_constructor(|| {
+            Histogram::new([0.5, 0.6, 0.7, 0.8, 0.85, 0.9, 0.925, 0.95, 0.975, 0.99, 1.0])
+        });
+        registry.register(
+            "cache_score",
+            "Similarity score of the closest cached answer to each lookup",
+            cache_scores.clone(),
+        );
+
+        let tokens = Family::<TokenLabels, Counter>::default();
+        registry.register("tokens", "Tokens spent, by model and kind", tokens.clone());
+
+        Self {
+            registry,
+            tool_calls,
+            in_flight,
+            duration,
+            http_responses,
+            http_retries,
+            cache_results,
+            cache_scores,
+            tokens,
+        }
+    }
+
+    // Renders every metric in the OpenMetrics text format
+    pub fn encode(&self) -> Result<String> {
+        let mut output = String::new();
+        encode(&mut output, &self.registry)?;
+        Ok(output)
+    }
+}
+
+fn tool_labels(tool: &str) -> ToolLabels {
+    ToolLabels {
+        tool: tool.to_string(),
+    }
+}
+
+#[async_trait]
+impl UsageReporter for PrometheusUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        let tool = tool_labels(&report.tool);
+
+        self.duration
+            .get_or_create(&tool)
+            .observe(report.latency.as_secs_f64());
+        self.cache_results
+            .get_or_create(&CacheLabels {
+                tool: report.tool.clone(),
+                result: report.cache.name().to_string(),
+            })
+            .inc();
+        if report.http_attempts > 1 {
+            self.http_retries
+                .get_or_create(&tool)
+                .inc_by(u64::from(report.http_attempts - 1));
+        }
+
+        let usage = report.usage;
+        for (kind, tokens) in [
+            ("prompt", usage.prompt_tokens),
+            ("completion", usage.completion_tokens),
+            ("citation", usage.citation_tokens),
+            ("reasoning", usage.reasoning_tokens),
+        ] {
+            if tokens > 0 {
+                self.tokens
+                    .get_or_create(&TokenLabels {
+                        model: report.model.clone(),
+                        kind: kind.to_string(),
+                    })
+                    .inc_by(tokens);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, event: UsageEvent<'_>) {
+        match event {
+            UsageEvent::CallStarted { tool } => {
+                self.tool_calls.get_or_create(&tool_labels(tool)).inc();
+                self.in_flight.get_or_create(&tool_labels(tool)).inc();
+            }
+            UsageEvent::CallFinished { tool } => {
+                self.in_flight.get_or_create(&tool_labels(tool)).dec();
+            }
+            UsageEvent::CacheLookup {
+                tool,
+                score: Some(score),
+            } => {
+                self.cache_scores
+                    .get_or_create(&tool_labels(tool))
+                    .observe(f64::from(score));
+            }
+            UsageEvent::CacheLookup { score: None, .. } => {}
+            UsageEvent::HttpResponse { tool, status } => {
+                self.http_responses
+                    .get_or_create(&HttpLabels {
+                        tool: tool.to_string(),
+                        status,
+                    })
+                    .inc();
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "prometheus"
+    }
+}
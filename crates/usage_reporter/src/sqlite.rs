@@ -0,0 +1,386 @@
+use std::{
+    fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{Connection, params};
+
+use crate::{UsageReport, UsageReporter};
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct SqliteUsageReporterConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsageGrouping {
+    Day,
+    Model,
+    Tool,
+    Client,
+}
+
+impl UsageGrouping {
+    fn column(&self) -> &'static str {
+        match self {
+            UsageGrouping::Day => "substr(timestamp, 1, 10)",
+            UsageGrouping::Model => "model",
+            UsageGrouping::Tool => "tool",
+            UsageGrouping::Client => "coalesce(client_name, 'unknown')",
+        }
+    }
+}
+
+impl FromStr for UsageGrouping {
+    type Err = anyhow::Error;
+
+    fn from_str(grouping: &str) -> Result<Self> {
+        match grouping {
+            "day" => Ok(UsageGrouping::Day),
+            "model" => Ok(UsageGrouping::Model),
+            "tool" => Ok(UsageGrouping::Tool),
+            "client" => Ok(UsageGrouping::Client),
+            other => Err(anyhow!("Invalid usage grouping: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for UsageGrouping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            UsageGrouping::Day => "day",
+            UsageGrouping::Model => "model",
+            UsageGrouping::Tool => "tool",
+            UsageGrouping::Client => "client",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct UsageSummary {
+    pub key: String,
+    pub calls: u64,
+    pub cache_hits: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub saved_tokens: u64,
+    pub cost: f64,
+    pub saved_cost: f64,
+}
+
+// Records every report as a row of a local SQLite database, which can then be
+// aggregated by day, model, tool or client
+pub struct SqliteUsageReporter {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteUsageReporter {
+    pub fn open(config: &SqliteUsageReporterConfig) -> Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(&config.path)
+            .with_context(|| format!("Failed to open usage ledger {}", config.path.display()))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                id INTEGER PRIMARY KEY,
+                timestamp TEXT NOT NULL,
+                tool TEXT NOT NULL,
+                request_id TEXT,
+                client_name TEXT,
+                model TEXT NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                total_tokens INTEGER NOT NULL,
+                citation_tokens INTEGER NOT NULL,
+                reasoning_tokens INTEGER NOT NULL,
+                num_search_queries INTEGER NOT NULL,
+                search_context_size TEXT,
+                cache TEXT NOT NULL,
+                cache_score REAL,
+                saved_tokens INTEGER,
+                latency_ms INTEGER NOT NULL,
+                http_attempts INTEGER NOT NULL,
+                cost REAL,
+                saved_cost REAL
+            );
+            CREATE INDEX IF NOT EXISTS usage_timestamp ON usage (timestamp);",
+        )?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    // Aggregates the calls made since `since`, or ever, most expensive group first
+    pub fn summarize(
+        &self,
+        grouping: UsageGrouping,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageSummary>> {
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow!("Usage ledger lock poisoned"))?;
+
+        let mut statement = connection.prepare(&format!(
+            "SELECT {} AS key,
+                COUNT(*),
+                SUM(cache IN ('hit', 'stale')),
+                SUM(prompt_tokens),
+                SUM(completion_tokens),
+                SUM(total_tokens),
+                COALESCE(SUM(saved_tokens), 0),
+                COALESCE(SUM(cost), 0.0),
+                COALESCE(SUM(saved_cost), 0.0)
+            FROM usage
+            WHERE timestamp >= ?1
+            GROUP BY key
+            ORDER BY 8 DESC, 2 DESC",
+            grouping.column()
+        ))?;
+
+        let since = since.map(timestamp).unwrap_or_default();
+        let summaries = statement
+            .query_map(params![since], |row| {
+                Ok(UsageSummary {
+                    key: row.get(0)?,
+                    calls: row.get(1)?,
+                    cache_hits: row.get(2)?,
+                    prompt_tokens: row.get(3)?,
+                    completion_tokens: row.get(4)?,
+                    total_tokens: row.get(5)?,
+                    saved_tokens: row.get(6)?,
+                    cost: row.get(7)?,
+                    saved_cost: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(summaries)
+    }
+
+    // Totals across every call made since `since`, or ever
+    pub fn total(&self, since: Option<DateTime<Utc>>) -> Result<UsageSummary> {
+        let totals = self.summarize(UsageGrouping::Day, since)?.into_iter().fold(
+            UsageSummary::default(),
+            |mut total, day| {
+                total.calls += day.calls;
+                total.cache_hits += day.cache_hits;
+                total.prompt_tokens += day.prompt_tokens;
+                total.completion_tokens += day.completion_tokens;
+                total.total_tokens += day.total_tokens;
+                total.saved_tokens += day.saved_tokens;
+                total.cost += day.cost;
+                total.saved_cost += day.saved_cost;
+                total
+            },
+        );
+
+        Ok(UsageSummary {
+            key: "total".into(),
+            ..totals
+        })
+    }
+}
+
+// Fixed width UTC timestamps, so that they compare as text
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn insert(connection: &Connection, report: &UsageReport) -> Result<()> {
+    let saved = report.cache.saved();
+
+    connection.execute(
+        "INSERT INTO usage (
+            timestamp, tool, request_id, client_name, model,
+            prompt_tokens, completion_tokens, total_tokens, citation_tokens,
+            reasoning_tokens, num_search_queries, search_context_size,
+            cache, cache_score, saved_tokens, latency_ms, http_attempts,
+            cost, saved_cost
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
+        params![
+            timestamp(report.timestamp),
+            report.tool,
+            report.request_id,
+            report.client_name,
+            report.model,
+            report.usage.prompt_tokens,
+            report.usage.completion_tokens,
+            report.usage.total_tokens,
+            report.usage.citation_tokens,
+            report.usage.reasoning_tokens,
+            report.usage.num_search_queries,
+            report.usage.search_context_size.map(|size| size.as_str()),
+            report.cache.name(),
+            report.cache.score(),
+            saved.map(|saved| saved.total_tokens),
+            report.latency.as_millis() as u64,
+            report.http_attempts,
+            report.cost,
+            report.saved_cost,
+        ],
+    )?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl UsageReporter for SqliteUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        self.report_batch(vec![report]).await
+    }
+
+    // Inserts the whole batch in one transaction
+    async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| anyhow!("Usage ledger lock poisoned"))?;
+
+            let transaction = connection.transaction()?;
+            for report in &reports {
+                insert(&transaction, report)?;
+            }
+            transaction.commit()?;
+
+            Ok(())
+        })
+        .await?
+    }
+
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{CacheStatus, Usage, test_report};
+
+    fn ledger(name: &str) -> Result<SqliteUsageReporter> {
+        let dir = std::env::temp_dir().join(format!(
+            "usage_reporter-sqlite-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+
+        SqliteUsageReporter::open(&SqliteUsageReporterConfig {
+            path: dir.join("usage.sqlite"),
+        })
+    }
+
+    fn report(
+        tool: &str,
+        model: &str,
+        client: Option<&str>,
+        cost: f64,
+        days_ago: i64,
+    ) -> UsageReport {
+        UsageReport {
+            timestamp: Utc::now() - Duration::days(days_ago),
+            model: model.into(),
+            client_name: client.map(Into::into),
+            cost: Some(cost),
+            ..test_report(tool)
+        }
+    }
+
+    fn cached(tool: &str, cache: CacheStatus) -> UsageReport {
+        UsageReport {
+            usage: Usage::default(),
+            cache,
+            ..test_report(tool)
+        }
+    }
+
+    fn keys(summaries: &[UsageSummary]) -> Vec<(&str, u64, f64)> {
+        summaries
+            .iter()
+            .map(|summary| (summary.key.as_str(), summary.calls, summary.cost))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn calls_are_grouped_most_expensive_first() -> Result<()> {
+        let ledger = ledger("grouping")?;
+        ledger
+            .report_batch(vec![
+                report("search", "sonar", Some("zed"), 0.25, 0),
+                report("search", "sonar-pro", Some("zed"), 0.5, 0),
+                report("find_apis", "sonar-pro", None, 2.0, 0),
+            ])
+            .await?;
+
+        assert_eq!(
+            keys(&ledger.summarize(UsageGrouping::Tool, None)?),
+            vec![("find_apis", 1, 2.0), ("search", 2, 0.75)]
+        );
+        assert_eq!(
+            keys(&ledger.summarize(UsageGrouping::Model, None)?),
+            vec![("sonar-pro", 2, 2.5), ("sonar", 1, 0.25)]
+        );
+        assert_eq!(
+            keys(&ledger.summarize(UsageGrouping::Client, None)?),
+            vec![("unknown", 1, 2.0), ("zed", 2, 0.75)]
+        );
+        assert_eq!(ledger.total(None)?.total_tokens, 300);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn periods_leave_out_older_calls() -> Result<()> {
+        let ledger = ledger("periods")?;
+        ledger
+            .report_batch(vec![
+                report("search", "sonar", None, 1.0, 0),
+                report("search", "sonar", None, 2.0, 3),
+                report("search", "sonar", None, 4.0, 10),
+            ])
+            .await?;
+
+        let since = |days| Some(Utc::now() - Duration::days(days));
+        assert_eq!(ledger.total(since(1))?.cost, 1.0);
+        assert_eq!(ledger.total(since(7))?.cost, 3.0);
+        assert_eq!(ledger.total(None)?.cost, 7.0);
+        assert_eq!(ledger.summarize(UsageGrouping::Day, since(7))?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_answers_from_the_cache_are_hits() -> Result<()> {
+        let ledger = ledger("hits")?;
+        let saved = Usage {
+            total_tokens: 100,
+            ..Usage::default()
+        };
+        ledger
+            .report_batch(vec![
+                test_report("search"),
+                cached("search", CacheStatus::Hit { score: 1.0, saved }),
+                cached("search", CacheStatus::Stale { score: 0.97, saved }),
+                cached("search", CacheStatus::Coalesced { saved }),
+            ])
+            .await?;
+
+        let total = ledger.total(None)?;
+        assert_eq!(total.calls, 4);
+        assert_eq!(total.cache_hits, 2);
+        // A coalesced call still saved the tokens of a request
+        assert_eq!(total.saved_tokens, 300);
+        Ok(())
+    }
+}
@@ -1,24 +1,246 @@
+mod budget;
+mod buffered;
+mod composite;
+mod jsonl;
+#[cfg(feature = "otel")]
+mod otel;
+mod pricing;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "webhook")]
+mod webhook;
+
+use std::time::Duration;
+
 use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+pub use crate::budget::{
+    BudgetConfig, BudgetExceeded, BudgetLimit, BudgetPeriod, BudgetScope, BudgetUsageReporter,
+    BudgetWarningHandler, RateLimit,
+};
+pub use crate::buffered::{BufferedUsageReporter, BufferedUsageReporterConfig};
+pub use crate::composite::CompositeUsageReporter;
+pub use crate::jsonl::{JsonlUsageReporter, JsonlUsageReporterConfig};
+#[cfg(feature = "otel")]
+pub use crate::otel::OtelUsageReporter;
+pub use crate::pricing::{ModelPrice, PricingTable, RequestFees};
+#[cfg(feature = "prometheus")]
+pub use crate::prometheus::PrometheusUsageReporter;
+#[cfg(feature = "sqlite")]
+pub use crate::sqlite::{
+    SqliteUsageReporter, SqliteUsageReporterConfig, UsageGrouping, UsageSummary,
+};
+#[cfg(feature = "webhook")]
+pub use crate::webhook::{
+    SIGNATURE_HEADER, WebhookUsageReporter, WebhookUsageReporterConfig, sign, verify_signature,
+};
 
+#[derive(Clone, Copy, Debug, Default)]
 pub struct Usage {
     pub completion_tokens: u64,
     pub prompt_tokens: u64,
     pub total_tokens: u64,
+    // Perplexity specific, zero for models that do not report them
+    pub citation_tokens: u64,
+    pub reasoning_tokens: u64,
+    pub num_search_queries: u64,
+    pub search_context_size: Option<SearchContextSize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchContextSize {
+    Low,
+    Medium,
+    High,
+}
+
+impl SearchContextSize {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchContextSize::Low => "low",
+            SearchContextSize::Medium => "medium",
+            SearchContextSize::High => "high",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum CacheStatus {
+    #[default]
+    Miss,
+    // Answered from the similarity cache, so `usage` is zero and `saved` holds
+    // the tokens the original call spent
+    Hit {
+        score: f32,
+        saved: Usage,
+    },
+    // Served from the cache past its maximum age while a fresh answer is fetched
+    Stale {
+        score: f32,
+        saved: Usage,
+    },
+    // Shared the response of an identical request that was already in flight
+    Coalesced {
+        saved: Usage,
+    },
+}
+
+impl CacheStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheStatus::Miss => "miss",
+            CacheStatus::Hit { .. } => "hit",
+            CacheStatus::Stale { .. } => "stale",
+            CacheStatus::Coalesced { .. } => "coalesced",
+        }
+    }
+
+    pub fn saved(&self) -> Option<Usage> {
+        match self {
+            CacheStatus::Miss => None,
+            CacheStatus::Hit { saved, .. }
+            | CacheStatus::Stale { saved, .. }
+            | CacheStatus::Coalesced { saved } => Some(*saved),
+        }
+    }
+
+    pub fn score(&self) -> Option<f32> {
+        match self {
+            CacheStatus::Hit { score, .. } | CacheStatus::Stale { score, .. } => Some(*score),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Clone, Debug)]
 pub struct UsageReport {
+    // When the call finished, which may be well before a buffered report is written
+    pub timestamp: DateTime<Utc>,
+    pub tool: String,
+    // The JSON-RPC id of the tool call, unset for background revalidations
+    pub request_id: Option<String>,
+    pub client_name: Option<String>,
     pub model: String,
     pub usage: Usage,
+    pub cache: CacheStatus,
+    pub latency: Duration,
+    // Estimated in dollars from the pricing table, `None` for unpriced models
+    pub cost: Option<f64>,
+    // What the tokens saved by the cache would have cost
+    pub saved_cost: Option<f64>,
+    // HTTP requests sent to Perplexity, zero when answered from the cache or
+    // by another request in flight
+    pub http_attempts: u32,
 }
 
+// What happens during a tool call besides its outcome, which a `UsageReport`
+// only describes once the call succeeds
+#[derive(Clone, Copy, Debug)]
+pub enum UsageEvent<'a> {
+    CallStarted { tool: &'a str },
+    CallFinished { tool: &'a str },
+    // The score of the closest cached answer, `None` when there was none
+    CacheLookup { tool: &'a str, score: Option<f32> },
+    HttpResponse { tool: &'a str, status: u16 },
+}
+
+#[async_trait]
 pub trait UsageReporter: Send + Sync {
-    fn report(&self, usage: UsageReport) -> Result<()>;
+    async fn report(&self, usage: UsageReport) -> Result<()>;
+
+    // Reporters that write several reports at once, such as to a file or a
+    // database, override this to do so
+    async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+        let mut result = Ok(());
+        for report in reports {
+            if let Err(err) = self.report(report).await {
+                result = Err(err);
+            }
+        }
+
+        result
+    }
+
+    // Waits for any buffered reports to be written
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Called before each Perplexity call on behalf of `client`, failing with
+    // `BudgetExceeded` to block it, and otherwise counting it against any rate
+    // limit. The reservation must be kept until the call has been reported
+    fn check_budget(&self, _tool: &str, _client: Option<&str>) -> Result<BudgetReservation> {
+        Ok(BudgetReservation::default())
+    }
+
+    // Must return quickly, as events are recorded on the request path
+    fn record(&self, _event: UsageEvent<'_>) {}
+
+    // Identifies the reporter in logs
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+// Spend held against budgets while a call is in flight, so that calls made at
+// the same time cannot all pass a check that only one of them fits in
+#[derive(Default)]
+pub struct BudgetReservation {
+    releases: Vec<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl BudgetReservation {
+    pub fn new(release: impl FnOnce() + Send + Sync + 'static) -> Self {
+        Self {
+            releases: vec![Box::new(release)],
+        }
+    }
+
+    pub fn join(mut self, mut other: BudgetReservation) -> Self {
+        self.releases.append(&mut other.releases);
+        self
+    }
+}
+
+impl Drop for BudgetReservation {
+    fn drop(&mut self) {
+        for release in self.releases.drain(..) {
+            release();
+        }
+    }
 }
 
 pub struct NoopUsageReporter;
 
+#[async_trait]
 impl UsageReporter for NoopUsageReporter {
-    fn report(&self, _usage: UsageReport) -> Result<()> {
+    async fn report(&self, _usage: UsageReport) -> Result<()> {
         Ok(())
     }
 }
+
+// A report of a call answered by Perplexity, for the reporters' tests
+#[cfg(test)]
+pub(crate) fn test_report(tool: &str) -> UsageReport {
+    UsageReport {
+        timestamp: Utc::now(),
+        tool: tool.into(),
+        request_id: None,
+        client_name: None,
+        model: "sonar".into(),
+        usage: Usage {
+            total_tokens: 100,
+            ..Usage::default()
+        },
+        cache: CacheStatus::Miss,
+        latency: Duration::ZERO,
+        cost: None,
+        saved_cost: None,
+        http_attempts: 1,
+    }
+}
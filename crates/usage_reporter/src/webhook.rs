@@ -0,0 +1,495 @@
+use std::{env, io::ErrorKind, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use http_client::{AsyncBody, HttpClient, Request};
+use sha2::Sha256;
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{UsageReport, UsageReporter, jsonl::UsageLine};
+
+// Carries `sha256=` followed by the hex HMAC-SHA256 of the request body
+pub const SIGNATURE_HEADER: &str = "X-Perplexity-MCP-Signature";
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct WebhookUsageReporterConfig {
+    pub url: String,
+    // The environment variable holding the signing secret
+    #[serde(default = "default_secret_env")]
+    pub secret_env: String,
+    // Batches the endpoint could not take wait here, one per line, until it is back
+    pub spool_path: PathBuf,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    // Doubled after each failed attempt
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+    #[serde(default = "default_max_spool_bytes")]
+    pub max_spool_bytes: u64,
+}
+
+fn default_secret_env() -> String {
+    "PERPLEXITY_MCP_WEBHOOK_SECRET".into()
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_spool_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    reports: Vec<UsageLine<'a>>,
+}
+
+enum Delivery {
+    Delivered,
+    // Refused for good, such as for a bad signature, so not worth retrying
+    Rejected(u16),
+    Failed(anyhow::Error),
+}
+
+fn mac(secret: &[u8], body: &[u8]) -> Result<Hmac<Sha256>> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret).map_err(|_| anyhow!("Invalid signing secret"))?;
+    mac.update(body);
+    Ok(mac)
+}
+
+pub fn sign(secret: &[u8], body: &[u8]) -> Result<String> {
+    let signature = mac(secret, body)?.finalize().into_bytes();
+    Ok(format!("sha256={}", hex::encode(signature)))
+}
+
+// Checks the signature header of a delivery in constant time
+pub fn verify_signature(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let Some(signature) = signature
+        .strip_prefix("sha256=")
+        .and_then(|signature| hex::decode(signature).ok())
+    else {
+        return false;
+    };
+
+    mac(secret, body).is_ok_and(|mac| mac.verify_slice(&signature).is_ok())
+}
+
+// POSTs each batch of reports as signed JSON, retrying a few times and then
+// spooling it to disk, so that nothing is lost while the endpoint is down
+pub struct WebhookUsageReporter {
+    config: WebhookUsageReporterConfig,
+    http_client: Arc<dyn HttpClient>,
+    secret: Vec<u8>,
+    spool_lock: Mutex<()>,
+}
+
+impl WebhookUsageReporter {
+    pub fn new(
+        config: WebhookUsageReporterConfig,
+        http_client: Arc<dyn HttpClient>,
+    ) -> Result<Self> {
+        let secret = env::var(&config.secret_env).with_context(|| {
+            format!(
+                "The usage webhook needs a signing secret in {}",
+                config.secret_env
+            )
+        })?;
+        if let Some(parent) = config.spool_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Ok(Self {
+            config,
+            http_client,
+            secret: secret.into_bytes(),
+            spool_lock: Mutex::new(()),
+        })
+    }
+
+    fn request(&self, body: &str) -> Result<Request<AsyncBody>> {
+        Ok(Request::builder()
+            .method("POST")
+            .uri(&self.config.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, sign(&self.secret, body.as_bytes())?)
+            .body(body.to_string().into())?)
+    }
+
+    async fn deliver(&self, body: &str) -> Delivery {
+        let request = match self.request(body) {
+            Ok(request) => request,
+            Err(err) => return Delivery::Failed(err),
+        };
+
+        match self.http_client.send(request).await {
+            Ok(response) if response.status().is_success() => Delivery::Delivered,
+            Ok(response) => {
+                let status = response.status().as_u16();
+                if (400..500).contains(&status) && status != 408 && status != 429 {
+                    Delivery::Rejected(status)
+                } else {
+                    Delivery::Failed(anyhow!("Usage webhook responded with {}", status))
+                }
+            }
+            Err(err) => Delivery::Failed(err),
+        }
+    }
+
+    async fn deliver_with_retries(&self, body: &str) -> Delivery {
+        let mut delay = Duration::from_millis(self.config.retry_delay_ms);
+        let mut attempt = 1;
+        loop {
+            match self.deliver(body).await {
+                Delivery::Failed(err) if attempt < self.config.max_attempts => {
+                    log::warn!("Usage webhook attempt {} failed: {}", attempt, err);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                delivery => return delivery,
+            }
+        }
+    }
+
+    async fn spool(&self, body: &str) -> Result<()> {
+        let _guard = self.spool_lock.lock().await;
+
+        let path = &self.config.spool_path;
+        let size = fs::metadata(path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        if size + body.len() as u64 > self.config.max_spool_bytes {
+            return Err(anyhow!(
+                "Usage webhook spool {} is full, dropping a batch",
+                path.display()
+            ));
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(format!("{}\n", body).as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    // Delivers spooled batches oldest first, stopping at the first that fails.
+    // Returns whether the spool is now empty
+    async fn drain_spool(&self) -> Result<bool> {
+        let _guard = self.spool_lock.lock().await;
+
+        let path = &self.config.spool_path;
+        let contents = match fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+            Err(err) => return Err(err.into()),
+        };
+        let batches = contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+
+        let mut sent = 0;
+        for body in &batches {
+            match self.deliver(body).await {
+                Delivery::Delivered => {}
+                Delivery::Rejected(status) => {
+                    log::error!(
+                        "Usage webhook rejected a spooled batch with {}, dropping it",
+                        status
+                    );
+                }
+                Delivery::Failed(_) => break,
+            }
+            sent += 1;
+        }
+
+        let remaining = &batches[sent..];
+        if remaining.is_empty() {
+            fs::remove_file(path).await?;
+            if sent > 0 {
+                log::info!("Delivered {} spooled usage batches", sent);
+            }
+            return Ok(true);
+        }
+        if sent > 0 {
+            let temp_path = path.with_extension("tmp");
+            fs::write(&temp_path, format!("{}\n", remaining.join("\n"))).await?;
+            fs::rename(&temp_path, path).await?;
+        }
+
+        Ok(false)
+    }
+}
+
+#[async_trait]
+impl UsageReporter for WebhookUsageReporter {
+    async fn report(&self, report: UsageReport) -> Result<()> {
+        self.report_batch(vec![report]).await
+    }
+
+    async fn report_batch(&self, reports: Vec<UsageReport>) -> Result<()> {
+        let body = serde_json::to_string(&Payload {
+            reports: reports.iter().map(UsageLine::from).collect(),
+        })?;
+
+        // Batches are delivered in order, so while older ones wait, so does this one
+        if !self.drain_spool().await? {
+            return self.spool(&body).await;
+        }
+
+        match self.deliver_with_retries(&body).await {
+            Delivery::Delivered => Ok(()),
+            Delivery::Rejected(status) => Err(anyhow!(
+                "Usage webhook rejected {} reports with {}",
+                reports.len(),
+                status
+            )),
+            Delivery::Failed(err) => {
+                log::warn!(
+                    "Spooling {} usage reports until the webhook is back: {}",
+                    reports.len(),
+                    err
+                );
+                self.spool(&body).await
+            }
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.drain_spool().await.map(|_| ())
+    }
+
+    fn name(&self) -> &str {
+        "webhook"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use http_client::Response;
+    use serde_json::Value;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::test_report;
+
+    const SECRET: &[u8] = b"Jefe";
+    const BODY: &[u8] = b"what do ya want for nothing?";
+
+    // Answers 503 a number of times, then takes every correctly signed batch
+    struct FakeEndpoint {
+        failures: AtomicU32,
+        attempts: AtomicU32,
+        delivered: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl FakeEndpoint {
+        fn new(failures: u32) -> Arc<Self> {
+            Arc::new(Self {
+                failures: AtomicU32::new(failures),
+                attempts: AtomicU32::new(0),
+                delivered: Mutex::new(Vec::new()),
+            })
+        }
+
+        // The tools of the reports in each batch received
+        async fn delivered(&self) -> Vec<Vec<String>> {
+            self.delivered.lock().await.clone()
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for FakeEndpoint {
+        async fn send(&self, request: Request<AsyncBody>) -> Result<Response<AsyncBody>> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| {
+                    failures.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                return Ok(Response::builder()
+                    .status(503)
+                    .body(AsyncBody::from(String::new()))?);
+            }
+
+            let signature = request
+                .headers()
+                .get(SIGNATURE_HEADER)
+                .and_then(|signature| signature.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let body = request.into_body().0;
+            if !verify_signature(SECRET, &body, &signature) {
+                return Ok(Response::builder()
+                    .status(401)
+                    .body(AsyncBody::from(String::new()))?);
+            }
+
+            let payload: Value = serde_json::from_slice(&body)?;
+            let tools = payload["reports"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|report| report["tool"].as_str().map(str::to_string))
+                .collect();
+            self.delivered.lock().await.push(tools);
+
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(String::new()))?)
+        }
+    }
+
+    fn reporter(name: &str, endpoint: Arc<FakeEndpoint>) -> WebhookUsageReporter {
+        let dir = std::env::temp_dir().join(format!(
+            "usage_reporter-webhook-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::create_dir_all(&dir);
+
+        WebhookUsageReporter {
+            config: WebhookUsageReporterConfig {
+                url: "http://usage.test/reports".into(),
+                secret_env: default_secret_env(),
+                spool_path: dir.join("spool.jsonl"),
+                max_attempts: 3,
+                retry_delay_ms: 20,
+                max_spool_bytes: default_max_spool_bytes(),
+            },
+            http_client: endpoint,
+            secret: SECRET.to_vec(),
+            spool_lock: Mutex::new(()),
+        }
+    }
+
+    fn spooled(reporter: &WebhookUsageReporter) -> usize {
+        std::fs::read_to_string(&reporter.config.spool_path)
+            .map(|contents| contents.lines().count())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried_with_backoff() -> Result<()> {
+        let endpoint = FakeEndpoint::new(2);
+        let reporter = reporter("retries", endpoint.clone());
+
+        let started = Instant::now();
+        reporter.report(test_report("search")).await?;
+
+        assert_eq!(endpoint.attempts.load(Ordering::SeqCst), 3);
+        // 20ms, then twice that
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert_eq!(endpoint.delivered().await, vec![vec!["search".to_string()]]);
+        assert_eq!(spooled(&reporter), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_are_spooled_until_the_endpoint_is_back() -> Result<()> {
+        // Every attempt of the first batch fails, then the retry of the spool
+        let endpoint = FakeEndpoint::new(4);
+        let reporter = reporter("spool", endpoint.clone());
+
+        reporter.report(test_report("search")).await?;
+        assert_eq!(spooled(&reporter), 1);
+
+        // Newer batches wait behind the spooled one
+        reporter
+            .report_batch(vec![
+                test_report("find_apis"),
+                test_report("get_documentation"),
+            ])
+            .await?;
+        assert_eq!(spooled(&reporter), 2);
+        assert!(endpoint.delivered().await.is_empty());
+
+        reporter.flush().await?;
+        reporter.flush().await?;
+
+        // Delivered once each, oldest first
+        assert_eq!(
+            endpoint.delivered().await,
+            vec![
+                vec!["search".to_string()],
+                vec!["find_apis".to_string(), "get_documentation".to_string()],
+            ]
+        );
+        assert!(!reporter.config.spool_path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spooled_batches_go_before_new_ones() -> Result<()> {
+        let endpoint = FakeEndpoint::new(3);
+        let reporter = reporter("order", endpoint.clone());
+
+        reporter.report(test_report("search")).await?;
+        reporter.report(test_report("find_apis")).await?;
+
+        assert_eq!(
+            endpoint.delivered().await,
+            vec![vec!["search".to_string()], vec!["find_apis".to_string()]]
+        );
+        assert_eq!(spooled(&reporter), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn signatures_are_hex_hmac_sha256() -> Result<()> {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(SECRET, BODY)?,
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signatures_verify_only_for_the_same_secret_and_body() -> Result<()> {
+        let signature = sign(SECRET, BODY)?;
+
+        assert!(verify_signature(SECRET, BODY, &signature));
+        assert!(!verify_signature(b"other", BODY, &signature));
+        assert!(!verify_signature(
+            SECRET,
+            b"what do ya want for everything?",
+            &signature
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_signatures_do_not_verify() -> Result<()> {
+        let signature = sign(SECRET, BODY)?;
+        let hex = signature.trim_start_matches("sha256=");
+
+        assert!(!verify_signature(SECRET, BODY, hex));
+        assert!(!verify_signature(SECRET, BODY, &format!("sha1={}", hex)));
+        assert!(!verify_signature(SECRET, BODY, "sha256=not hex"));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &signature[..signature.len() - 2]
+        ));
+        assert!(!verify_signature(SECRET, BODY, ""));
+        Ok(())
+    }
+}
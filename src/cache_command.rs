@@ -0,0 +1,155 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+};
+
+use anyhow::{Result, anyhow, bail};
+use perplexity_mcp_tools::format_cache_entry;
+use similarity_cache::{CacheSelector, Cipher, export_jsonl, import_jsonl};
+
+use crate::config::Config;
+
+const USAGE: &str = "Usage: perplexity-mcp cache <command>
+
+Commands:
+  list [--limit N]                 List the most recent cached entries
+  search <text> [--limit N]        Find cached entries close to a text
+  delete --id ID                   Delete a single entry
+  delete --action TOOL             Delete every entry cached for a tool
+  delete --older-than-days N       Delete entries older than N days
+  clear                            Delete every entry
+  export [FILE]                    Write entries as JSON lines to FILE or stdout
+  import [FILE]                    Read entries as JSON lines from FILE or stdin
+  keygen                           Print a new key for `cache.encryption`
+  encrypt                          Encrypt the entries and history written before
+                                   encryption was enabled";
+
+pub(crate) fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
+}
+
+fn limit(args: &[String]) -> Result<usize> {
+    flag_value(args, "--limit")
+        .map(|limit| {
+            limit
+                .parse()
+                .map_err(|_| anyhow!("Invalid --limit: {}", limit))
+        })
+        .unwrap_or(Ok(20))
+}
+
+pub async fn run(config: &Config, args: &[String]) -> Result<()> {
+    if args.first().is_some_and(|command| command == "keygen") {
+        println!("{}", Cipher::generate_key());
+        return Ok(());
+    }
+    if args.first().is_some_and(|command| command == "encrypt") {
+        return encrypt(config).await;
+    }
+
+    if !config.cache.is_persistent() {
+        bail!(
+            "No persistent cache configured, set `cache.path` or `cache.redis` in the config file"
+        );
+    }
+
+    let cache = config.cache.similarity_cache().await?;
+    let cache = cache.as_ref();
+
+    match args.first().map(String::as_str) {
+        Some("list") => {
+            let entries = cache.entries().await?;
+            for entry in entries.iter().rev().take(limit(args)?) {
+                println!("{}", format_cache_entry(entry));
+            }
+            eprintln!("{} cached entries", entries.len());
+        }
+        Some("search") => {
+            let text = args
+                .get(1)
+                .filter(|text| !text.starts_with("--"))
+                .ok_or_else(|| anyhow!("Missing search text\n\n{}", USAGE))?;
+
+            for (score, entry) in similarity_cache::search(cache, text, limit(args)?).await? {
+                println!("{:.3}  {}", score, format_cache_entry(&entry));
+            }
+        }
+        Some("delete") => {
+            let selector = if let Some(id) = flag_value(args, "--id") {
+                CacheSelector::Id(id.to_string())
+            } else if let Some(action) = flag_value(args, "--action") {
+                CacheSelector::Action(action.to_string())
+            } else if let Some(days) = flag_value(args, "--older-than-days") {
+                let days = days
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid --older-than-days: {}", days))?;
+                CacheSelector::older_than_days(days)?
+            } else {
+                bail!("Missing delete selector\n\n{}", USAGE);
+            };
+
+            println!("Deleted {} cached entries", cache.delete(&selector).await?);
+        }
+        Some("clear") => {
+            println!(
+                "Deleted {} cached entries",
+                cache.delete(&CacheSelector::All).await?
+            );
+        }
+        Some("export") => {
+            let exported = match args.get(1) {
+                Some(path) => export_jsonl(cache, BufWriter::new(File::create(path)?)).await?,
+                None => export_jsonl(cache, io::stdout()).await?,
+            };
+            eprintln!("Exported {} cached entries", exported);
+        }
+        Some("import") => {
+            let imported = match args.get(1) {
+                Some(path) => import_jsonl(cache, BufReader::new(File::open(path)?)).await?,
+                None => import_jsonl(cache, BufReader::new(io::stdin())).await?,
+            };
+            eprintln!("Imported {} cached entries", imported);
+        }
+        _ => bail!("{}", USAGE),
+    }
+
+    Ok(())
+}
+
+// Plain text left from before encryption was enabled is refused everywhere
+// else, so that it is never taken for encrypted data
+async fn encrypt(config: &Config) -> Result<()> {
+    let mut encrypted = false;
+
+    if config.cache.is_persistent()
+        && let Some(cipher) = config.cache.cipher()?
+    {
+        // Files are encrypted as they are opened, Redis entries are stored again
+        let cache = config
+            .cache
+            .open(Some(cipher.migrating_plaintext()))
+            .await?;
+        let entries = cache.entries().await?;
+        if config.cache.redis.is_some() {
+            for entry in entries.iter().cloned() {
+                cache.store(entry).await?;
+            }
+        }
+        eprintln!("Encrypted {} cached entries", entries.len());
+        encrypted = true;
+    }
+
+    if let Some(entries) = config.history.encrypt()? {
+        eprintln!("Encrypted {} history entries", entries);
+        encrypted = true;
+    }
+
+    if !encrypted {
+        bail!("Nothing to encrypt, set `cache.encryption` or `history.encryption` with a path");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use context_server::PromptExecutor;
+use perplexity_mcp_tools::ResearchPrompt;
+use serde_json::{Value, json};
+
+// Completes the arguments of the research prompts, which the context server's
+// registry has no notion of
+pub struct Completions {
+    prompts: Vec<Arc<ResearchPrompt>>,
+}
+
+impl Completions {
+    pub fn new(prompts: Vec<Arc<ResearchPrompt>>) -> Self {
+        Self { prompts }
+    }
+
+    // Answers `completion/complete`, leaving other messages to the caller
+    pub fn handle(&self, message: &Value) -> Option<Value> {
+        if message["method"] != "completion/complete" {
+            return None;
+        }
+
+        let params = &message["params"];
+        let values = match params["ref"]["type"].as_str() {
+            Some("ref/prompt") => self
+                .prompts
+                .iter()
+                .find(|prompt| params["ref"]["name"] == prompt.name())
+                .map(|prompt| {
+                    prompt.complete(
+                        params["argument"]["name"].as_str().unwrap_or_default(),
+                        params["argument"]["value"].as_str().unwrap_or_default(),
+                    )
+                })
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        Some(json!({
+            "jsonrpc": "2.0",
+            "id": message["id"].clone(),
+            "result": {
+                "completion": {
+                    "values": values,
+                    "total": values.len(),
+                    "hasMore": false
+                }
+            }
+        }))
+    }
+
+    // Lets the client know it can ask for completions
+    pub fn advertise(response: &mut Value) {
+        if let Some(capabilities) = response["result"]["capabilities"].as_object_mut() {
+            capabilities.insert("completions".into(), json!({}));
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, env, fs, path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use http_client::HttpClient;
+use perplexity_mcp_tools::CachePolicy;
+use similarity_cache::{
+    Cipher, EncryptionConfig, FileSimilarityCache, HnswParams, HnswSimilarityCache,
+    InMemorySimilarityCache, RedisCacheConfig, RedisSimilarityCache, SimilarityCache,
+};
+use usage_reporter::{
+    BudgetConfig, BudgetUsageReporter, BudgetWarningHandler, BufferedUsageReporter,
+    BufferedUsageReporterConfig, CompositeUsageReporter, JsonlUsageReporter,
+    JsonlUsageReporterConfig, ModelPrice, PricingTable, SqliteUsageReporter,
+    SqliteUsageReporterConfig, UsageReporter, WebhookUsageReporter, WebhookUsageReporterConfig,
+};
+
+use crate::{
+    history::HistoryConfig, prometheus::PrometheusConfig, telemetry::TelemetryConfig,
+    watch::WatchConfig,
+};
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub cache: CacheConfig,
+    pub usage: UsageConfig,
+    // Serve every answer from the cache and never call Perplexity
+    pub offline: bool,
+    pub telemetry: TelemetryConfig,
+    // Serves Prometheus metrics over HTTP when set
+    pub prometheus: Option<PrometheusConfig>,
+    // Past answers, exposed as `perplexity://history` resources
+    pub history: HistoryConfig,
+    // Topics asked again for subscribers of `perplexity://watch` resources
+    pub watch: WatchConfig,
+}
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheIndex {
+    // Scans every entry, fine up to a few thousand answers
+    #[default]
+    Flat,
+    Hnsw,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub path: Option<PathBuf>,
+    pub index: CacheIndex,
+    pub hnsw: HnswParams,
+    // When set, the cache lives in Redis and `path` and `index` are ignored
+    pub redis: Option<RedisCacheConfig>,
+    // Encrypts the cache file or HNSW index at `path`
+    pub encryption: Option<EncryptionConfig>,
+    pub admin_tool: bool,
+    // Replaces the default of every tool missing from `tools`
+    pub default_policy: Option<CachePolicy>,
+    pub tools: HashMap<String, CachePolicy>,
+}
+
+impl CacheConfig {
+    // The policy configured for `tool`, or else the tool's own default
+    pub fn policy(&self, tool: &str, tool_default: CachePolicy) -> CachePolicy {
+        if !self.enabled {
+            return CachePolicy::Disabled;
+        }
+
+        self.tools
+            .get(tool)
+            .copied()
+            .or(self.default_policy)
+            .unwrap_or(tool_default)
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some() || self.redis.is_some()
+    }
+
+    pub fn cipher(&self) -> Result<Option<Cipher>> {
+        self.encryption
+            .as_ref()
+            .map(EncryptionConfig::cipher)
+            .transpose()
+    }
+
+    pub async fn similarity_cache(&self) -> Result<Arc<dyn SimilarityCache>> {
+        self.open(self.cipher()?).await
+    }
+
+    pub async fn open(&self, cipher: Option<Cipher>) -> Result<Arc<dyn SimilarityCache>> {
+        if let Some(redis) = &self.redis {
+            return Ok(Arc::new(
+                RedisSimilarityCache::connect(redis, cipher).await?,
+            ));
+        }
+
+        match (self.index, &self.path) {
+            (CacheIndex::Flat, Some(path)) => {
+                Ok(Arc::new(FileSimilarityCache::open(path, cipher)?))
+            }
+            (CacheIndex::Flat, None) => Ok(Arc::new(InMemorySimilarityCache::new())),
+            (CacheIndex::Hnsw, Some(path)) => Ok(Arc::new(HnswSimilarityCache::open(
+                path, self.hnsw, cipher,
+            )?)),
+            (CacheIndex::Hnsw, None) => Ok(Arc::new(HnswSimilarityCache::new(self.hnsw))),
+        }
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub jsonl: Option<JsonlUsageReporterConfig>,
+    // Records usage in a SQLite ledger, which backs `perplexity-mcp usage` and `usage://summary`
+    pub sqlite: Option<SqliteUsageReporterConfig>,
+    // POSTs signed batches of reports to an HTTP endpoint
+    pub webhook: Option<WebhookUsageReporterConfig>,
+    // How reports are queued and batched before being written
+    pub buffer: BufferedUsageReporterConfig,
+    // Replaces the built-in prices of the listed models
+    pub pricing: HashMap<String, ModelPrice>,
+    // Append the estimated cost of each call to the tool output
+    pub show_cost: bool,
+    pub budgets: BudgetConfig,
+}
+
+impl UsageConfig {
+    pub fn pricing(&self) -> PricingTable {
+        PricingTable::default().with_overrides(self.pricing.clone())
+    }
+
+    pub fn ledger(&self) -> Result<Option<Arc<SqliteUsageReporter>>> {
+        self.sqlite
+            .as_ref()
+            .map(|sqlite| Ok(Arc::new(SqliteUsageReporter::open(sqlite)?)))
+            .transpose()
+    }
+
+    pub fn usage_reporter(
+        &self,
+        http_client: Arc<dyn HttpClient>,
+        ledger: Option<Arc<SqliteUsageReporter>>,
+        exporters: Vec<Arc<dyn UsageReporter>>,
+        on_budget_warning: BudgetWarningHandler,
+    ) -> Result<Option<Arc<dyn UsageReporter>>> {
+        let mut reporters: Vec<Arc<dyn UsageReporter>> = Vec::new();
+        if let Some(jsonl) = &self.jsonl {
+            reporters.push(Arc::new(JsonlUsageReporter::new(jsonl.clone())?));
+        }
+        if let Some(ledger) = ledger {
+            reporters.push(ledger);
+        }
+        if let Some(webhook) = &self.webhook {
+            reporters.push(Arc::new(WebhookUsageReporter::new(
+                webhook.clone(),
+                http_client,
+            )?));
+        }
+        reporters.extend(exporters);
+
+        let reporter: Option<Arc<dyn UsageReporter>> = match reporters.len() {
+            0 => None,
+            1 => reporters.pop(),
+            _ => Some(Arc::new(CompositeUsageReporter::new(reporters))),
+        };
+        // Budgets must see each call as it happens, so only the writes are buffered
+        let reporter = reporter.map(|reporter| {
+            Arc::new(BufferedUsageReporter::new(self.buffer, reporter)) as Arc<dyn UsageReporter>
+        });
+
+        if self.budgets.limits.is_empty() && self.budgets.rate_limits.is_empty() {
+            return Ok(reporter);
+        }
+
+        Ok(Some(Arc::new(
+            BudgetUsageReporter::new(self.budgets.clone(), reporter)?
+                .with_warning_handler(on_budget_warning),
+        )))
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let mut config = match env::var_os("PERPLEXITY_MCP_CONFIG").map(PathBuf::from) {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+                serde_json::from_str(&contents)
+                    .with_context(|| format!("Failed to parse config file {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        // Quick to flip on a plane without editing the config file
+        if env::var_os("PERPLEXITY_MCP_OFFLINE").is_some_and(|offline| offline != "0") {
+            config.offline = true;
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use similarity_cache::{Cipher, EncryptionConfig};
+
+// Tools whose results are research worth keeping
+const RESEARCH_TOOLS: &[&str] = &[
+    "search",
+    "get_documentation",
+    "find_apis",
+    "check_deprecated_code",
+];
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    // Keeps the history across restarts, otherwise it lasts as long as the server
+    pub path: Option<PathBuf>,
+    // Older calls are forgotten past this many
+    pub max_entries: usize,
+    // Encrypts each entry in the file at `path`
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            max_entries: 200,
+            encryption: None,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn open(&self) -> Result<Option<History>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let cipher = self
+            .encryption
+            .as_ref()
+            .map(EncryptionConfig::cipher)
+            .transpose()?;
+
+        History::open(self.path.clone(), cipher, self.max_entries.max(1)).map(Some)
+    }
+
+    // Encrypts the entries written to `path` before `encryption` was set,
+    // returning how many the history holds
+    pub fn encrypt(&self) -> Result<Option<usize>> {
+        let (Some(path), Some(encryption)) = (&self.path, &self.encryption) else {
+            return Ok(None);
+        };
+
+        let cipher = encryption.cipher()?.migrating_plaintext();
+        let history = History::open(Some(path.clone()), Some(cipher), self.max_entries.max(1))?;
+        Ok(Some(history.list().len()))
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub tool: String,
+    pub arguments: Value,
+    pub answer: String,
+    pub citations: Vec<String>,
+    pub client_name: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl HistoryEntry {
+    // The argument that carries the question, whichever tool was called
+    pub fn query(&self) -> String {
+        ["query", "requirement", "code", "topic"]
+            .iter()
+            .find_map(|key| self.arguments[key].as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| self.arguments.to_string())
+    }
+}
+
+// The answers of past research tool calls, newest last
+pub struct History {
+    path: Option<PathBuf>,
+    cipher: Option<Cipher>,
+    max_entries: usize,
+    entries: RwLock<VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    fn open(path: Option<PathBuf>, cipher: Option<Cipher>, max_entries: usize) -> Result<Self> {
+        let mut entries = VecDeque::new();
+        let mut plaintext = false;
+
+        if let Some(path) = &path {
+            if path.exists() {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open history {}", path.display()))?;
+                for (index, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    // Rather than lose the history to a wrong key, refuse to start
+                    let (line, id) = decrypt(line, cipher.as_ref()).with_context(|| {
+                        format!("Failed to read line {} of {}", index + 1, path.display())
+                    })?;
+                    plaintext |= id.is_none();
+                    match serde_json::from_str::<HistoryEntry>(&line) {
+                        Ok(entry) if id.is_some_and(|id| id != entry.id) => {
+                            return Err(anyhow!(
+                                "Line {} of {} holds an entry other than the one it was encrypted for",
+                                index + 1,
+                                path.display()
+                            ));
+                        }
+                        Ok(entry) => entries.push_back(entry),
+                        Err(err) => eprintln!(
+                            "Skipping invalid history entry on line {} of {}: {}",
+                            index + 1,
+                            path.display(),
+                            err
+                        ),
+                    }
+                }
+            } else if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        while entries.len() > max_entries {
+            entries.pop_front();
+        }
+
+        let history = Self {
+            path,
+            cipher,
+            max_entries,
+            entries: RwLock::new(entries),
+        };
+
+        // Plain text is only read along with a cipher while migrating
+        if plaintext && history.cipher.is_some() {
+            let entries = history
+                .entries
+                .read()
+                .map_err(|_| anyhow!("History lock poisoned"))?;
+            history.rewrite(&entries)?;
+        }
+
+        Ok(history)
+    }
+
+    // Keeps the answer of a successful tool call, given the result sent back
+    pub fn record(
+        &self,
+        tool: &str,
+        arguments: &Value,
+        result: &Value,
+        client_name: Option<String>,
+    ) {
+        if !RESEARCH_TOOLS.contains(&tool) || result["isError"] == true {
+            return;
+        }
+
+        let answer = result_text(result);
+        let citations = result["_meta"]["citations"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|citation| citation.as_str().map(str::to_string))
+            .collect();
+
+        if let Err(err) = self.push(HistoryEntry {
+            id: String::new(),
+            tool: tool.to_string(),
+            arguments: arguments.clone(),
+            answer,
+            citations,
+            client_name,
+            created_at: Utc::now(),
+        }) {
+            eprintln!("Error recording history: {}", err);
+        }
+    }
+
+    fn push(&self, mut entry: HistoryEntry) -> Result<()> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| anyhow!("History lock poisoned"))?;
+
+        // Ids follow creation time, so that they sort like the history
+        let mut id = entry.created_at.timestamp_millis().max(0) as u64;
+        while entries
+            .iter()
+            .any(|existing| existing.id == format!("{:x}", id))
+        {
+            id += 1;
+        }
+        entry.id = format!("{:x}", id);
+
+        entries.push_back(entry);
+        let trimmed = entries.len() > self.max_entries;
+        if trimmed {
+            entries.pop_front();
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if trimmed {
+            self.rewrite(&entries)?;
+        } else if let Some(entry) = entries.back() {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", self.encode(entry)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn rewrite(&self, entries: &VecDeque<HistoryEntry>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut contents = String::new();
+        for entry in entries {
+            contents.push_str(&self.encode(entry)?);
+            contents.push('\n');
+        }
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, path)?;
+
+        Ok(())
+    }
+
+    fn encode(&self, entry: &HistoryEntry) -> Result<String> {
+        let line = serde_json::to_string(entry)?;
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&entry.id, line.as_bytes()),
+            None => Ok(line),
+        }
+    }
+
+    // Newest first
+    pub fn list(&self) -> Vec<HistoryEntry> {
+        self.entries
+            .read()
+            .map(|entries| entries.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, id: &str) -> Option<HistoryEntry> {
+        self.entries
+            .read()
+            .ok()?
+            .iter()
+            .find(|entry| entry.id == id)
+            .cloned()
+    }
+}
+
+// The text of a tool result, its contents joined
+pub fn result_text(result: &Value) -> String {
+    result["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|content| content["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+// The entry on a line and, when it was encrypted, the id it was encrypted for
+fn decrypt(line: String, cipher: Option<&Cipher>) -> Result<(String, Option<String>)> {
+    if line.starts_with('{') {
+        if let Some(cipher) = cipher {
+            cipher.accept_plaintext("History entry")?;
+        }
+        return Ok((line, None));
+    }
+
+    let cipher = cipher
+        .ok_or_else(|| anyhow!("History is encrypted but no encryption key is configured"))?;
+    let id = Cipher::record_id(&line)?.to_string();
+    let line = String::from_utf8(cipher.decrypt(&id, &line)?)?;
+    Ok((line, Some(id)))
+}
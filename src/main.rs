@@ -1,42 +1,186 @@
-use std::{env, sync::Arc};
+mod cache_command;
+mod completions;
+mod config;
+mod history;
+mod prometheus;
+mod resources;
+mod telemetry;
+mod usage_command;
+mod watch;
 
-use anyhow::Result;
-use context_server::{ContextServer, ContextServerRpcRequest, ContextServerRpcResponse};
+use std::{
+    env,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Result, anyhow};
+use context_server::{ContextServer, ContextServerRpcRequest};
 use context_server_utils::{
     prompt_registry::PromptRegistry, resource_registry::ResourceRegistry,
     tool_registry::ToolRegistry,
 };
 use http_client::HttpClient;
 use http_client_reqwest::HttpClientReqwest;
+use opentelemetry::{
+    Context, KeyValue, global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+};
 use perplexity_mcp_tools::{
-    CheckDeprecatedCodeTool, FindApisTool, GetDocumentationTool, SearchTool,
+    CacheAdminTool, CheckDeprecatedCodeTool, FindApisTool, GetDocumentationTool, SearchTool,
+    ToolCallContext, research_prompts, with_tool_call,
+};
+use serde_json::{Value, json};
+use similarity_cache::{PassthroughSimilarityCache, SimilarityCache};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+use usage_reporter::{BudgetWarningHandler, SqliteUsageReporter, UsageReporter};
+
+use crate::{
+    completions::Completions,
+    config::Config,
+    history::History,
+    resources::{ResourceTemplate, Resources},
+    telemetry::TRACER_NAME,
+    watch::{ResourceUpdateHandler, Watches},
 };
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
 
 struct ContextServerState {
     rpc: ContextServer,
+    resources: Resources,
+    completions: Completions,
+    history: Option<Arc<History>>,
+    client_name: RwLock<Option<String>>,
 }
 
 impl ContextServerState {
-    fn new(http_client: Arc<dyn HttpClient>) -> Result<Self> {
+    fn new(
+        http_client: Arc<dyn HttpClient>,
+        similarity_cache: Arc<dyn SimilarityCache>,
+        usage_reporter: Option<Arc<dyn UsageReporter>>,
+        ledger: Option<Arc<SqliteUsageReporter>>,
+        history: Option<Arc<History>>,
+        on_resource_update: ResourceUpdateHandler,
+        config: &Config,
+    ) -> Result<Self> {
+        let pricing = Arc::new(config.usage.pricing());
+
         let resource_registry = Arc::new(ResourceRegistry::default());
 
         let tool_registry = Arc::new(ToolRegistry::default());
 
-        tool_registry.register(Arc::new(SearchTool::new(http_client.clone(), None, None)));
-        tool_registry.register(Arc::new(GetDocumentationTool::new(
-            http_client.clone(),
-            None,
-            None,
-        )));
-        tool_registry.register(Arc::new(FindApisTool::new(http_client.clone(), None, None)));
-        tool_registry.register(Arc::new(CheckDeprecatedCodeTool::new(
-            http_client.clone(),
-            None,
-            None,
-        )));
+        let search_tool = Arc::new(
+            SearchTool::new(
+                http_client.clone(),
+                usage_reporter.clone(),
+                Some(similarity_cache.clone()),
+            )
+            .with_cache_policy(
+                config
+                    .cache
+                    .policy("search", SearchTool::DEFAULT_CACHE_POLICY),
+            )
+            .with_offline(config.offline)
+            .with_pricing(pricing.clone())
+            .with_cost_in_output(config.usage.show_cost),
+        );
+        tool_registry.register(search_tool.clone());
+        let documentation_tool = Arc::new(
+            GetDocumentationTool::new(
+                http_client.clone(),
+                usage_reporter.clone(),
+                Some(similarity_cache.clone()),
+            )
+            .with_cache_policy(config.cache.policy(
+                "get_documentation",
+                GetDocumentationTool::DEFAULT_CACHE_POLICY,
+            ))
+            .with_offline(config.offline)
+            .with_pricing(pricing.clone())
+            .with_cost_in_output(config.usage.show_cost),
+        );
+        tool_registry.register(documentation_tool.clone());
+        let find_apis_tool = Arc::new(
+            FindApisTool::new(
+                http_client.clone(),
+                usage_reporter.clone(),
+                Some(similarity_cache.clone()),
+            )
+            .with_cache_policy(
+                config
+                    .cache
+                    .policy("find_apis", FindApisTool::DEFAULT_CACHE_POLICY),
+            )
+            .with_offline(config.offline)
+            .with_pricing(pricing.clone())
+            .with_cost_in_output(config.usage.show_cost),
+        );
+        tool_registry.register(find_apis_tool.clone());
+        let deprecated_code_tool = Arc::new(
+            CheckDeprecatedCodeTool::new(
+                http_client.clone(),
+                usage_reporter.clone(),
+                Some(similarity_cache.clone()),
+            )
+            .with_cache_policy(config.cache.policy(
+                "check_deprecated_code",
+                CheckDeprecatedCodeTool::DEFAULT_CACHE_POLICY,
+            ))
+            .with_offline(config.offline)
+            .with_pricing(pricing.clone())
+            .with_cost_in_output(config.usage.show_cost),
+        );
+        tool_registry.register(deprecated_code_tool.clone());
+        if config.cache.admin_tool {
+            tool_registry.register(Arc::new(CacheAdminTool::new(similarity_cache)));
+        }
+
+        let watches = Arc::new(Watches::new(
+            config.watch.clone(),
+            vec![
+                search_tool.clone(),
+                documentation_tool.clone(),
+                find_apis_tool.clone(),
+                deprecated_code_tool,
+            ],
+            history.clone(),
+            on_resource_update,
+        ));
+
+        // For clients that would rather attach context than call tools
+        let templates = vec![
+            ResourceTemplate {
+                uri_prefix: "perplexity://search/",
+                name: "Search",
+                description: "The answer to a search query, as the search tool gives it",
+                argument: "query",
+                tool: search_tool,
+            },
+            ResourceTemplate {
+                uri_prefix: "perplexity://docs/",
+                name: "Documentation",
+                description: "Documentation and usage examples for a library, API or technology",
+                argument: "query",
+                tool: documentation_tool,
+            },
+            ResourceTemplate {
+                uri_prefix: "perplexity://apis/",
+                name: "APIs",
+                description: "APIs that could meet a requirement, and how they compare",
+                argument: "requirement",
+                tool: find_apis_tool,
+            },
+        ];
 
         let prompt_registry = Arc::new(PromptRegistry::default());
+        let prompts = research_prompts()
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+        for prompt in &prompts {
+            prompt_registry.register(prompt.clone());
+        }
 
         Ok(Self {
             rpc: ContextServer::builder()
@@ -45,46 +189,264 @@ impl ContextServerState {
                 .with_tools(tool_registry)
                 .with_prompts(prompt_registry)
                 .build()?,
+            resources: Resources::new(ledger, history.clone())
+                .with_templates(templates)
+                .with_watches(watches),
+            completions: Completions::new(prompts),
+            history,
+            client_name: RwLock::new(None),
         })
     }
 
-    async fn process_request(
-        &self,
-        request: ContextServerRpcRequest,
-    ) -> Result<Option<ContextServerRpcResponse>> {
-        self.rpc.handle_incoming_message(request).await
+    fn tool_call_context(&self, message: &Value) -> ToolCallContext {
+        if message["method"] == "initialize"
+            && let Some(name) = message["params"]["clientInfo"]["name"].as_str()
+            && let Ok(mut client_name) = self.client_name.write()
+        {
+            *client_name = Some(name.to_string());
+        }
+
+        ToolCallContext {
+            request_id: match &message["id"] {
+                Value::Null => None,
+                Value::String(id) => Some(id.clone()),
+                id => Some(id.to_string()),
+            },
+            client_name: self
+                .client_name
+                .read()
+                .ok()
+                .and_then(|client_name| client_name.clone()),
+        }
+    }
+
+    async fn process_request(&self, message: Value) -> Result<Option<Value>> {
+        let context = self.tool_call_context(&message);
+        if let Some(response) = self.resources.handle(&message, &context).await {
+            return Ok(Some(response));
+        }
+        if let Some(response) = self.completions.handle(&message) {
+            return Ok(Some(response));
+        }
+        let initialize = message["method"] == "initialize";
+        // Kept to record the answer in the history
+        let tool_call = (message["method"] == "tools/call")
+            .then(|| (message.clone(), context.client_name.clone()));
+
+        let cx = tool_call_span(&message, &context);
+        let request: ContextServerRpcRequest = serde_json::from_value(message)?;
+
+        let (response, meta) = with_tool_call(context, self.rpc.handle_incoming_message(request))
+            .with_context(cx.clone())
+            .await;
+
+        let Some(response) =
+            response.inspect_err(|err| cx.span().set_status(Status::error(err.to_string())))?
+        else {
+            return Ok(None);
+        };
+
+        // Tools attach metadata such as cache hits out of band, as `_meta` on the
+        // result, and flag failures meant for the model through `isError`
+        let mut response = serde_json::to_value(response)?;
+        if initialize {
+            Completions::advertise(&mut response);
+            self.resources.advertise(&mut response);
+        }
+        if let Some(result) = response.get_mut("result").and_then(Value::as_object_mut) {
+            if !meta.meta.is_empty() {
+                result.insert("_meta".into(), Value::Object(meta.meta));
+            }
+            if meta.is_error {
+                result.insert("isError".into(), Value::Bool(true));
+                cx.span()
+                    .set_status(Status::error("Tool returned an error"));
+            }
+        }
+        if let Some(message) = response["error"]["message"].as_str() {
+            cx.span().set_status(Status::error(message.to_string()));
+        }
+        if let (Some(history), Some((message, client_name)), Some(result)) =
+            (&self.history, tool_call, response.get("result"))
+        {
+            let params = &message["params"];
+            let tool = params["name"].as_str().unwrap_or_default();
+            history.record(tool, &params["arguments"], result, client_name);
+        }
+
+        Ok(Some(response))
+    }
+}
+
+// A span for each `tools/call`, parent to the cache lookup and HTTP spans the
+// tool opens. Other requests are not traced
+fn tool_call_span(message: &Value, context: &ToolCallContext) -> Context {
+    if message["method"] != "tools/call" {
+        return Context::new();
+    }
+
+    let tool = message["params"]["name"].as_str().unwrap_or_default();
+    let mut attributes = vec![
+        KeyValue::new("mcp.method.name", "tools/call"),
+        KeyValue::new("gen_ai.tool.name", tool.to_string()),
+    ];
+    if let Some(request_id) = &context.request_id {
+        attributes.push(KeyValue::new("jsonrpc.request.id", request_id.clone()));
+    }
+    if let Some(client_name) = &context.client_name {
+        attributes.push(KeyValue::new("mcp.client.name", client_name.clone()));
     }
+
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(format!("tools/call {}", tool))
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start(&tracer);
+
+    Context::current_with_span(span)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let config = Config::load()?;
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some(command) = args.first() {
+        return match command.as_str() {
+            "cache" => cache_command::run(&config, &args[1..]).await,
+            "usage" => usage_command::run(&config, &args[1..]),
+            _ => Err(anyhow!("Unknown command: {}", command)),
+        };
+    }
+
     let http_client = Arc::new(HttpClientReqwest::default());
 
-    if env::var("PERPLEXITY_API_KEY").is_err() {
+    if config.offline {
+        if !config.cache.enabled || !config.cache.is_persistent() {
+            eprintln!(
+                "Offline mode needs a persistent cache (cache.path or cache.redis) to answer from"
+            );
+            std::process::exit(1);
+        }
+    } else if env::var("PERPLEXITY_API_KEY").is_err() {
         eprintln!("PERPLEXITY_API_KEY environment variable is required");
         std::process::exit(1);
     }
 
-    let state = ContextServerState::new(http_client)?;
+    let similarity_cache: Arc<dyn SimilarityCache> = if config.cache.enabled {
+        config.cache.similarity_cache().await?
+    } else {
+        Arc::new(PassthroughSimilarityCache::new())
+    };
+
+    // Requests are handled concurrently, their responses and any notifications
+    // funnelled through a single writer
+    let (responses_tx, mut responses_rx) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = io::stdout();
+        while let Some(response) = responses_rx.recv().await {
+            let response_json = serde_json::to_string(&response)?;
+            stdout.write_all(response_json.as_bytes()).await?;
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await?;
+        }
+        anyhow::Ok(())
+    });
+
+    // Notifications hold the channel weakly, so that the usage reporter kept
+    // for flushing at exit does not keep the writer waiting
+    let notifications_tx = responses_tx.downgrade();
+    let on_budget_warning: BudgetWarningHandler = Arc::new(move |message| {
+        let Some(notifications_tx) = notifications_tx.upgrade() else {
+            return;
+        };
+        let _ = notifications_tx.send(json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/message",
+            "params": {
+                "level": "warning",
+                "logger": "budget",
+                "data": message
+            }
+        }));
+    });
+    let notifications_tx = responses_tx.downgrade();
+    let on_resource_update: ResourceUpdateHandler = Arc::new(move |uri| {
+        let Some(notifications_tx) = notifications_tx.upgrade() else {
+            return;
+        };
+        let _ = notifications_tx.send(json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/resources/updated",
+            "params": { "uri": uri }
+        }));
+    });
+    let telemetry = config.telemetry.init()?;
+    let mut exporters: Vec<Arc<dyn UsageReporter>> = Vec::new();
+    if let Some(telemetry) = &telemetry {
+        exporters.push(telemetry.usage_reporter());
+    }
+    if let Some(prometheus) = &config.prometheus {
+        exporters.push(prometheus::serve(prometheus).await?);
+    }
+
+    let ledger = config.usage.ledger()?;
+    let usage_reporter = config.usage.usage_reporter(
+        http_client.clone(),
+        ledger.clone(),
+        exporters,
+        on_budget_warning,
+    )?;
+
+    let history = config.history.open()?.map(Arc::new);
+
+    let state = Arc::new(ContextServerState::new(
+        http_client,
+        similarity_cache,
+        usage_reporter.clone(),
+        ledger,
+        history,
+        on_resource_update,
+        &config,
+    )?);
 
     let mut stdin = BufReader::new(io::stdin()).lines();
-    let mut stdout = io::stdout();
 
     while let Some(line) = stdin.next_line().await? {
-        let request: ContextServerRpcRequest = match serde_json::from_str(&line) {
-            Ok(req) => req,
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
             Err(e) => {
                 eprintln!("Error parsing request: {}", e);
                 continue;
             }
         };
 
-        if let Some(response) = state.process_request(request).await? {
-            let response_json = serde_json::to_string(&response)?;
-            stdout.write_all(response_json.as_bytes()).await?;
-            stdout.write_all(b"\n").await?;
-            stdout.flush().await?;
-        }
+        let state = state.clone();
+        let responses_tx = responses_tx.clone();
+        tokio::spawn(async move {
+            match state.process_request(message).await {
+                Ok(Some(response)) => {
+                    let _ = responses_tx.send(response);
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Error processing request: {}", e),
+            }
+        });
+    }
+
+    // Let in-flight requests finish before exiting, each holding a sender
+    drop(state);
+    drop(responses_tx);
+    writer.await??;
+
+    if let Some(usage_reporter) = usage_reporter
+        && let Err(err) = usage_reporter.flush().await
+    {
+        eprintln!("Error flushing usage reports: {}", err);
+    }
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
     }
 
     Ok(())
@@ -0,0 +1,83 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use usage_reporter::PrometheusUsageReporter;
+
+#[derive(Clone, serde::Deserialize)]
+pub struct PrometheusConfig {
+    pub listen: SocketAddr,
+}
+
+// Serves `GET /metrics` from a reporter fed like any other, for as long as the
+// server runs
+pub async fn serve(config: &PrometheusConfig) -> Result<Arc<PrometheusUsageReporter>> {
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .with_context(|| format!("Failed to listen for metrics scrapes on {}", config.listen))?;
+    let reporter = Arc::new(PrometheusUsageReporter::new());
+
+    let metrics = reporter.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = respond(stream, &metrics).await {
+                            eprintln!("Error answering metrics scrape: {}", err);
+                        }
+                    });
+                }
+                Err(err) => eprintln!("Error accepting metrics scrape: {}", err),
+            }
+        }
+    });
+
+    Ok(reporter)
+}
+
+async fn respond(mut stream: TcpStream, metrics: &PrometheusUsageReporter) -> Result<()> {
+    // Only the request line matters, but the rest of the head is read so that
+    // the client sees a response rather than a reset
+    let mut reader = BufReader::new((&mut stream).take(16 * 1024));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default().split('?').next();
+    let (status, content_type, body) = match (method, path) {
+        ("GET", Some("/metrics")) => (
+            "200 OK",
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            metrics.encode()?,
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "Not found\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
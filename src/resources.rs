@@ -0,0 +1,382 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use context_server::ToolExecutor;
+use perplexity_mcp_tools::{ToolCallContext, with_tool_call};
+use serde_json::{Value, json};
+use usage_reporter::SqliteUsageReporter;
+
+use crate::{
+    history::{History, HistoryEntry, result_text},
+    usage_command::usage_summary,
+    watch::{WATCH_URI, Watches},
+};
+
+const USAGE_SUMMARY_URI: &str = "usage://summary";
+const USAGE_SUMMARY_DAYS: u64 = 30;
+const HISTORY_URI: &str = "perplexity://history";
+
+// JSON-RPC error code for unknown resources
+const RESOURCE_NOT_FOUND: i64 = -32002;
+const INTERNAL_ERROR: i64 = -32603;
+
+// A family of resources read by calling a tool, such as
+// `perplexity://search/{query}`, the rest of the URI becoming `argument`
+pub struct ResourceTemplate {
+    pub uri_prefix: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub argument: &'static str,
+    pub tool: Arc<dyn ToolExecutor>,
+}
+
+// The resources this server exposes, answered here rather than through the
+// context server's registry
+pub struct Resources {
+    ledger: Option<Arc<SqliteUsageReporter>>,
+    history: Option<Arc<History>>,
+    templates: Vec<ResourceTemplate>,
+    watches: Option<Arc<Watches>>,
+}
+
+impl Resources {
+    pub fn new(ledger: Option<Arc<SqliteUsageReporter>>, history: Option<Arc<History>>) -> Self {
+        Self {
+            ledger,
+            history,
+            templates: Vec::new(),
+            watches: None,
+        }
+    }
+
+    pub fn with_templates(mut self, templates: Vec<ResourceTemplate>) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    pub fn with_watches(mut self, watches: Arc<Watches>) -> Self {
+        self.watches = Some(watches);
+        self
+    }
+
+    fn list_templates(&self) -> Vec<Value> {
+        let mut templates = self
+            .templates
+            .iter()
+            .map(|template| {
+                json!({
+                    "uriTemplate": format!("{}{{{}}}", template.uri_prefix, template.argument),
+                    "name": template.name,
+                    "description": template.description,
+                    "mimeType": "text/markdown"
+                })
+            })
+            .collect::<Vec<_>>();
+        if self.watches.is_some() {
+            templates.push(json!({
+                "uriTemplate": format!("{}/{{id}}", WATCH_URI),
+                "name": "Watch",
+                "description": "The latest answer to a configured topic or past research, asked again while subscribed",
+                "mimeType": "text/markdown"
+            }));
+        }
+
+        templates
+    }
+
+    // Asks Perplexity, or the cache, through the template's tool, as a tool
+    // call would, budgets and history included
+    async fn read_template(
+        &self,
+        template: &ResourceTemplate,
+        uri: &str,
+        value: &str,
+        context: &ToolCallContext,
+    ) -> Result<Value> {
+        let value = percent_decode(value)?;
+        if value.trim().is_empty() {
+            return Err(anyhow!("{} needs a {}", uri, template.argument));
+        }
+
+        let tool = template.tool.to_tool().name;
+        let arguments = json!({ template.argument: value });
+        let (content, meta) = with_tool_call(
+            context.clone(),
+            template.tool.execute(Some(arguments.clone())),
+        )
+        .await;
+        let result = json!({
+            "content": content?,
+            "_meta": meta.meta,
+            "isError": meta.is_error
+        });
+        if meta.is_error {
+            return Err(anyhow!(result_text(&result)));
+        }
+
+        if let Some(history) = &self.history {
+            history.record(&tool, &arguments, &result, context.client_name.clone());
+        }
+
+        Ok(json!({
+            "uri": uri,
+            "mimeType": "text/markdown",
+            "text": format!("# {}\n\n{}\n", value, result_text(&result)),
+            "_meta": result["_meta"]
+        }))
+    }
+
+    fn list(&self) -> Vec<Value> {
+        let mut resources = Vec::new();
+        if self.ledger.is_some() {
+            resources.push(json!({
+                "uri": USAGE_SUMMARY_URI,
+                "name": "Usage summary",
+                "description": "Perplexity calls, tokens and estimated cost over the last 30 days, by day, model, tool and client",
+                "mimeType": "application/json"
+            }));
+        }
+        if let Some(history) = &self.history {
+            resources.push(json!({
+                "uri": HISTORY_URI,
+                "name": "Research history",
+                "description": "Past search, documentation, API and deprecation answers, newest first",
+                "mimeType": "application/json"
+            }));
+            for entry in history.list() {
+                resources.push(json!({
+                    "uri": history_uri(&entry),
+                    "name": format!("{}: {}", entry.tool, truncate(&entry.query(), 80)),
+                    "description": format!("Answered {}", entry.created_at.to_rfc3339()),
+                    "mimeType": "text/markdown"
+                }));
+            }
+        }
+
+        if let Some(watches) = &self.watches {
+            resources.extend(watches.list());
+        }
+
+        resources
+    }
+
+    async fn read(&self, uri: &str, context: &ToolCallContext) -> Result<Option<Value>> {
+        for template in &self.templates {
+            if let Some(value) = uri.strip_prefix(template.uri_prefix) {
+                return self
+                    .read_template(template, uri, value, context)
+                    .await
+                    .map(Some);
+            }
+        }
+
+        if let (Some(watches), Some(id)) = (&self.watches, watch_id(uri)) {
+            return Ok(watches.read(id, context).await?.map(|text| {
+                json!({
+                    "uri": uri,
+                    "mimeType": "text/markdown",
+                    "text": text
+                })
+            }));
+        }
+
+        if let Some(history) = &self.history {
+            if uri == HISTORY_URI {
+                let entries = history
+                    .list()
+                    .iter()
+                    .map(|entry| {
+                        json!({
+                            "uri": history_uri(entry),
+                            "tool": entry.tool,
+                            "query": entry.query(),
+                            "citations": entry.citations,
+                            "client_name": entry.client_name,
+                            "created_at": entry.created_at
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                return Ok(Some(json!({
+                    "uri": uri,
+                    "mimeType": "application/json",
+                    "text": serde_json::to_string_pretty(&entries)?
+                })));
+            }
+            if let Some(entry) = uri
+                .strip_prefix(HISTORY_URI)
+                .and_then(|id| id.strip_prefix('/'))
+                .and_then(|id| history.get(id))
+            {
+                return Ok(Some(json!({
+                    "uri": uri,
+                    "mimeType": "text/markdown",
+                    "text": history_markdown(&entry)
+                })));
+            }
+        }
+
+        let contents = match (uri, &self.ledger) {
+            (USAGE_SUMMARY_URI, Some(ledger)) => {
+                serde_json::to_string_pretty(&usage_summary(ledger, USAGE_SUMMARY_DAYS)?)?
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(json!({
+            "uri": uri,
+            "mimeType": "application/json",
+            "text": contents
+        })))
+    }
+
+    // Lets the client know it can subscribe to watches
+    pub fn advertise(&self, response: &mut Value) {
+        if self.watches.is_none() {
+            return;
+        }
+        if let Some(capabilities) = response["result"]["capabilities"].as_object_mut() {
+            let resources = capabilities.entry("resources").or_insert_with(|| json!({}));
+            if let Some(resources) = resources.as_object_mut() {
+                resources.insert("subscribe".into(), json!(true));
+            }
+        }
+    }
+
+    // Answers `resources/list`, `resources/templates/list`, `resources/read` and
+    // the subscriptions to watches, leaving other messages to the caller
+    pub async fn handle(&self, message: &Value, context: &ToolCallContext) -> Option<Value> {
+        let id = message["id"].clone();
+
+        let result = match message["method"].as_str()? {
+            "resources/list" => json!({ "resources": self.list() }),
+            "resources/templates/list" => json!({ "resourceTemplates": self.list_templates() }),
+            "resources/read" => {
+                let uri = message["params"]["uri"].as_str().unwrap_or_default();
+                match self.read(uri, context).await {
+                    Ok(Some(contents)) => json!({ "contents": [contents] }),
+                    Ok(None) => {
+                        return Some(rpc_error(
+                            id,
+                            RESOURCE_NOT_FOUND,
+                            format!("Resource not found: {}", uri),
+                        ));
+                    }
+                    Err(err) => return Some(rpc_error(id, INTERNAL_ERROR, err.to_string())),
+                }
+            }
+            method @ ("resources/subscribe" | "resources/unsubscribe") => {
+                let uri = message["params"]["uri"].as_str().unwrap_or_default();
+                let subscribed = match (&self.watches, watch_id(uri)) {
+                    (Some(watches), Some(id)) if method == "resources/subscribe" => {
+                        watches.subscribe(id, context)
+                    }
+                    (Some(watches), Some(id)) => watches.unsubscribe(id).map(|_| true),
+                    _ => Ok(false),
+                };
+                match subscribed {
+                    Ok(true) => json!({}),
+                    Ok(false) => {
+                        return Some(rpc_error(
+                            id,
+                            RESOURCE_NOT_FOUND,
+                            format!("Resource cannot be subscribed to: {}", uri),
+                        ));
+                    }
+                    Err(err) => return Some(rpc_error(id, INTERNAL_ERROR, err.to_string())),
+                }
+            }
+            _ => return None,
+        };
+
+        Some(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": result
+        }))
+    }
+}
+
+fn watch_id(uri: &str) -> Option<&str> {
+    uri.strip_prefix(WATCH_URI)?
+        .strip_prefix('/')
+        .filter(|id| !id.is_empty())
+}
+
+// Decodes the `%XX` escapes of a URI segment
+fn percent_decode(text: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid escape in resource URI: {}", text))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn history_uri(entry: &HistoryEntry) -> String {
+    format!("{}/{}", HISTORY_URI, entry.id)
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+// The answer as the model first saw it, references included, headed by what
+// was asked and when
+fn history_markdown(entry: &HistoryEntry) -> String {
+    format!(
+        "# {}\n\nAsked with `{}` on {}.\n\n{}\n",
+        entry.query(),
+        entry.tool,
+        entry.created_at.to_rfc3339(),
+        entry.answer
+    )
+}
+
+fn rpc_error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_are_decoded() -> Result<()> {
+        assert_eq!(percent_decode("tokio%20select%21")?, "tokio select!");
+        assert_eq!(percent_decode("%2Fusr%2fbin")?, "/usr/bin");
+        assert_eq!(percent_decode("a+b")?, "a+b");
+        assert_eq!(percent_decode("caf%C3%A9")?, "café");
+        assert_eq!(percent_decode("")?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_escapes_are_an_error() {
+        assert!(percent_decode("100%").is_err());
+        assert!(percent_decode("%2").is_err());
+        assert!(percent_decode("%zz").is_err());
+        assert!(percent_decode("%+1").is_err());
+        assert!(percent_decode("%C3").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use opentelemetry::global;
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::SdkTracerProvider,
+};
+use usage_reporter::{OtelUsageReporter, UsageReporter};
+
+pub const TRACER_NAME: &str = "perplexity-mcp";
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    // Base URL of an OTLP/HTTP collector, otherwise `OTEL_EXPORTER_OTLP_ENDPOINT`
+    // or http://localhost:4318
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    pub metrics_interval_ms: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: None,
+            service_name: "perplexity-mcp".into(),
+            metrics_interval_ms: 60_000,
+        }
+    }
+}
+
+// Exports a span per tool call, and metrics from every usage report, until shut down
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl TelemetryConfig {
+    pub fn init(&self) -> Result<Option<Telemetry>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let mut span_exporter = SpanExporter::builder().with_http();
+        let mut metric_exporter = MetricExporter::builder().with_http();
+        if let Some(endpoint) = &self.endpoint {
+            let endpoint = endpoint.trim_end_matches('/');
+            span_exporter = span_exporter.with_endpoint(format!("{}/v1/traces", endpoint));
+            metric_exporter = metric_exporter.with_endpoint(format!("{}/v1/metrics", endpoint));
+        }
+
+        let resource = Resource::builder()
+            .with_service_name(self.service_name.clone())
+            .build();
+
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(span_exporter.build()?)
+            .with_resource(resource.clone())
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(metric_exporter.build()?)
+                    .with_interval(Duration::from_millis(self.metrics_interval_ms.max(1)))
+                    .build(),
+            )
+            .with_resource(resource)
+            .build();
+
+        global::set_tracer_provider(tracer_provider.clone());
+        global::set_meter_provider(meter_provider.clone());
+
+        Ok(Some(Telemetry {
+            tracer_provider,
+            meter_provider,
+        }))
+    }
+}
+
+impl Telemetry {
+    pub fn usage_reporter(&self) -> Arc<dyn UsageReporter> {
+        Arc::new(OtelUsageReporter::new(&global::meter(TRACER_NAME)))
+    }
+
+    // Exports whatever has not been yet
+    pub fn shutdown(self) {
+        if let Err(err) = self.tracer_provider.shutdown() {
+            eprintln!("Error exporting traces: {}", err);
+        }
+        if let Err(err) = self.meter_provider.shutdown() {
+            eprintln!("Error exporting metrics: {}", err);
+        }
+    }
+}
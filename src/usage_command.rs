@@ -0,0 +1,94 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::{Value, json};
+use usage_reporter::{SqliteUsageReporter, UsageGrouping, UsageSummary};
+
+use crate::{cache_command::flag_value, config::Config};
+
+const USAGE: &str = "Usage: perplexity-mcp usage [--by day|model|tool|client] [--days N] [--json]
+
+Summarizes the calls recorded in the usage ledger, over the last 30 days by default";
+
+const DEFAULT_DAYS: u64 = 30;
+
+fn since(days: u64) -> Result<DateTime<Utc>> {
+    i64::try_from(days)
+        .ok()
+        .and_then(TimeDelta::try_days)
+        .and_then(|period| Utc::now().checked_sub_signed(period))
+        .ok_or_else(|| anyhow!("{} days is too far back", days))
+}
+
+// Every grouping at once, as served by the `usage://summary` resource
+pub fn usage_summary(ledger: &SqliteUsageReporter, days: u64) -> Result<Value> {
+    let since = since(days)?;
+
+    Ok(json!({
+        "since": since,
+        "total": ledger.total(Some(since))?,
+        "by_day": ledger.summarize(UsageGrouping::Day, Some(since))?,
+        "by_model": ledger.summarize(UsageGrouping::Model, Some(since))?,
+        "by_tool": ledger.summarize(UsageGrouping::Tool, Some(since))?,
+        "by_client": ledger.summarize(UsageGrouping::Client, Some(since))?,
+    }))
+}
+
+fn print_summary(grouping: UsageGrouping, since: DateTime<Utc>, rows: &[UsageSummary]) {
+    println!(
+        "{:<24} {:>7} {:>7} {:>12} {:>12} {:>10} {:>10}",
+        grouping, "calls", "cached", "tokens", "saved", "cost", "saved $"
+    );
+    for row in rows {
+        println!(
+            "{:<24} {:>7} {:>7} {:>12} {:>12} {:>10.4} {:>10.4}",
+            row.key,
+            row.calls,
+            row.cache_hits,
+            row.total_tokens,
+            row.saved_tokens,
+            row.cost,
+            row.saved_cost
+        );
+    }
+    eprintln!("Since {}", since.format("%Y-%m-%d %H:%M UTC"));
+}
+
+pub fn run(config: &Config, args: &[String]) -> Result<()> {
+    if args.first().is_some_and(|arg| !arg.starts_with("--")) {
+        bail!("{}", USAGE);
+    }
+
+    let ledger = config.usage.ledger()?.ok_or_else(|| {
+        anyhow!("No usage ledger configured, set `usage.sqlite.path` in the config file")
+    })?;
+
+    let days = flag_value(args, "--days")
+        .map(|days| {
+            days.parse::<u64>()
+                .map_err(|_| anyhow!("Invalid --days: {}", days))
+        })
+        .unwrap_or(Ok(DEFAULT_DAYS))?;
+
+    if args.iter().any(|arg| arg == "--json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&usage_summary(&ledger, days)?)?
+        );
+        return Ok(());
+    }
+
+    let grouping = flag_value(args, "--by")
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or(UsageGrouping::Day);
+    let since = since(days)?;
+
+    let mut rows = ledger.summarize(grouping, Some(since))?;
+    if grouping == UsageGrouping::Day {
+        rows.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+    rows.push(ledger.total(Some(since))?);
+    print_summary(grouping, since, &rows);
+
+    Ok(())
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use context_server::ToolExecutor;
+use perplexity_mcp_tools::{ToolCallContext, with_tool_call};
+use serde_json::{Value, json};
+use similarity_cache::{cosine_similarity, embed};
+use tokio::task::JoinHandle;
+
+use crate::history::{History, result_text};
+
+pub const WATCH_URI: &str = "perplexity://watch";
+
+pub type ResourceUpdateHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct WatchConfig {
+    // How often a subscribed topic is asked again
+    pub interval_minutes: u64,
+    // Answers less similar than this to the previous one count as changed
+    pub min_similarity: f32,
+    // Topics by id, watched as `perplexity://watch/{id}`. History entries can
+    // be watched by their id too
+    pub topics: HashMap<String, WatchTopic>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            interval_minutes: 360,
+            min_similarity: 0.9,
+            topics: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct WatchTopic {
+    pub query: String,
+    #[serde(default = "default_tool")]
+    pub tool: String,
+    pub interval_minutes: Option<u64>,
+}
+
+fn default_tool() -> String {
+    "search".into()
+}
+
+// The argument each research tool takes its question in
+fn query_argument(tool: &str) -> &'static str {
+    match tool {
+        "find_apis" => "requirement",
+        "check_deprecated_code" => "code",
+        _ => "query",
+    }
+}
+
+struct Watch {
+    tool: String,
+    arguments: Value,
+    query: String,
+    interval: Duration,
+    // Re-runs are made, and paid for, on behalf of the client that subscribed
+    context: ToolCallContext,
+    answer: Option<String>,
+    checked_at: Option<DateTime<Utc>>,
+    changed_at: Option<DateTime<Utc>>,
+    task: Option<JoinHandle<()>>,
+}
+
+// Topics asked again on a schedule while a client is subscribed, which is
+// notified when the answer changes in substance rather than in wording
+pub struct Watches {
+    config: WatchConfig,
+    tools: HashMap<String, Arc<dyn ToolExecutor>>,
+    history: Option<Arc<History>>,
+    on_update: ResourceUpdateHandler,
+    watches: Mutex<HashMap<String, Watch>>,
+}
+
+impl Watches {
+    pub fn new(
+        config: WatchConfig,
+        tools: Vec<Arc<dyn ToolExecutor>>,
+        history: Option<Arc<History>>,
+        on_update: ResourceUpdateHandler,
+    ) -> Self {
+        Self {
+            config,
+            tools: tools
+                .into_iter()
+                .map(|tool| (tool.to_tool().name, tool))
+                .collect(),
+            history,
+            on_update,
+            watches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Value> {
+        let mut ids = self.config.topics.keys().collect::<Vec<_>>();
+        ids.sort();
+
+        ids.into_iter()
+            .map(|id| {
+                json!({
+                    "uri": format!("{}/{}", WATCH_URI, id),
+                    "name": format!("Watch: {}", id),
+                    "description": self.config.topics[id].query,
+                    "mimeType": "text/markdown"
+                })
+            })
+            .collect()
+    }
+
+    // Starts watching `id` unless it already is, from a configured topic or
+    // else a history entry
+    fn resolve(&self, id: &str, context: &ToolCallContext) -> Result<bool> {
+        let mut watches = self.lock()?;
+        if watches.contains_key(id) {
+            return Ok(true);
+        }
+
+        let (tool, query, mut arguments, interval_minutes) = match self.config.topics.get(id) {
+            Some(topic) => (
+                topic.tool.clone(),
+                topic.query.clone(),
+                json!({ query_argument(&topic.tool): topic.query }),
+                topic.interval_minutes,
+            ),
+            // Asked again as it was first asked, filters included
+            None => match self.history.as_ref().and_then(|history| history.get(id)) {
+                Some(entry) => (entry.tool.clone(), entry.query(), entry.arguments, None),
+                None => return Ok(false),
+            },
+        };
+        if !self.tools.contains_key(&tool) {
+            return Err(anyhow!("Topics cannot be watched with {}", tool));
+        }
+        // A cached answer would never change, so each re-run asks Perplexity
+        // and replaces it with the fresh answer
+        if let Some(arguments) = arguments.as_object_mut() {
+            arguments.insert("cache".into(), json!("refresh"));
+        }
+
+        watches.insert(
+            id.to_string(),
+            Watch {
+                tool,
+                arguments,
+                query,
+                interval: Duration::from_secs(
+                    interval_minutes
+                        .unwrap_or(self.config.interval_minutes)
+                        .max(1)
+                        * 60,
+                ),
+                context: context.clone(),
+                answer: None,
+                checked_at: None,
+                changed_at: None,
+                task: None,
+            },
+        );
+
+        Ok(true)
+    }
+
+    pub async fn read(&self, id: &str, context: &ToolCallContext) -> Result<Option<String>> {
+        if !self.resolve(id, context)? {
+            return Ok(None);
+        }
+        if self
+            .lock()?
+            .get(id)
+            .is_some_and(|watch| watch.answer.is_none())
+        {
+            self.check(id).await?;
+        }
+
+        let watches = self.lock()?;
+        let Some(watch) = watches.get(id) else {
+            return Ok(None);
+        };
+        let when = |time: Option<DateTime<Utc>>| {
+            time.map(|time| time.to_rfc3339())
+                .unwrap_or_else(|| "never".into())
+        };
+
+        Ok(Some(format!(
+            "# {}\n\nWatched with `{}` every {} minutes. Last checked {}, last changed {}.\n\n{}\n",
+            watch.query,
+            watch.tool,
+            watch.interval.as_secs() / 60,
+            when(watch.checked_at),
+            when(watch.changed_at),
+            watch.answer.as_deref().unwrap_or_default()
+        )))
+    }
+
+    pub fn subscribe(self: &Arc<Self>, id: &str, context: &ToolCallContext) -> Result<bool> {
+        if !self.resolve(id, context)? {
+            return Ok(false);
+        }
+
+        let mut watches = self.lock()?;
+        let Some(watch) = watches.get_mut(id) else {
+            return Ok(false);
+        };
+        if watch.task.is_none() {
+            // Holds on weakly, so that the watches go when the server does
+            let watches = Arc::downgrade(self);
+            watch.task = Some(tokio::spawn(run(watches, id.to_string(), watch.interval)));
+        }
+
+        Ok(true)
+    }
+
+    // Forgets the watch along with the context of the client that subscribed,
+    // so that whoever subscribes next pays for the re-runs
+    pub fn unsubscribe(&self, id: &str) -> Result<()> {
+        if let Some(task) = self.lock()?.remove(id).and_then(|watch| watch.task) {
+            task.abort();
+        }
+
+        Ok(())
+    }
+
+    // Asks again, returning whether the answer changed in substance since the
+    // last time
+    async fn check(&self, id: &str) -> Result<bool> {
+        let (tool, arguments, context) = {
+            let watches = self.lock()?;
+            let watch = watches
+                .get(id)
+                .ok_or_else(|| anyhow!("{} is not watched", id))?;
+            (
+                self.tools[&watch.tool].clone(),
+                watch.arguments.clone(),
+                watch.context.clone(),
+            )
+        };
+
+        let (content, meta) = with_tool_call(context, tool.execute(Some(arguments))).await;
+        let result = json!({ "content": content?, "_meta": meta.meta });
+        let answer = result_text(&result);
+        if meta.is_error {
+            return Err(anyhow!(answer));
+        }
+
+        let mut watches = self.lock()?;
+        let Some(watch) = watches.get_mut(id) else {
+            return Ok(false);
+        };
+        let now = Utc::now();
+        let changed = watch
+            .answer
+            .as_deref()
+            .is_some_and(|previous| differs(previous, &answer, self.config.min_similarity));
+        if changed || watch.answer.is_none() {
+            watch.changed_at = Some(now);
+        }
+        watch.answer = Some(answer);
+        watch.checked_at = Some(now);
+
+        Ok(changed)
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Watch>>> {
+        self.watches
+            .lock()
+            .map_err(|_| anyhow!("Watch lock poisoned"))
+    }
+}
+
+impl Drop for Watches {
+    fn drop(&mut self) {
+        if let Ok(watches) = self.watches.get_mut() {
+            for task in watches.values_mut().filter_map(|watch| watch.task.take()) {
+                task.abort();
+            }
+        }
+    }
+}
+
+async fn run(watches: Weak<Watches>, id: String, interval: Duration) {
+    let uri = format!("{}/{}", WATCH_URI, id);
+    let mut first = true;
+    loop {
+        // The first check only sets the answer to compare with, unless a
+        // read already did
+        if !first {
+            tokio::time::sleep(interval).await;
+        }
+
+        let Some(watches) = watches.upgrade() else {
+            return;
+        };
+        let has_answer = watches
+            .lock()
+            .map(|watches| watches.get(&id).is_some_and(|watch| watch.answer.is_some()))
+            .unwrap_or(false);
+        if first && has_answer {
+            first = false;
+            continue;
+        }
+        first = false;
+
+        match watches.check(&id).await {
+            Ok(true) => (watches.on_update)(&uri),
+            Ok(false) => {}
+            Err(err) => eprintln!("Error checking {}: {}", uri, err),
+        }
+    }
+}
+
+// Rewording alone is not a change, but new versions or dates are, as is an
+// answer that reads differently enough
+fn differs(previous: &str, current: &str, min_similarity: f32) -> bool {
+    let (previous, current) = (substance(previous), substance(current));
+    figures(previous) != figures(current)
+        || cosine_similarity(&embed(previous), &embed(current)) < min_similarity
+}
+
+// The answer without its references, whose URLs vary from one call to the
+// next, nor the reasoning that reasoning models give before it
+fn substance(answer: &str) -> &str {
+    let answer = answer
+        .split_once("\n\nReferences:\n")
+        .map_or(answer, |(content, _)| content);
+
+    answer
+        .trim_start()
+        .strip_prefix("<think>")
+        .and_then(|thinking| thinking.split_once("</think>"))
+        .map_or(answer, |(_, answer)| answer.trim_start())
+}
+
+// Versions and dates, such as 1.42.0, v2.0 or 2026-10-18
+fn figures(text: &str) -> BTreeSet<&str> {
+    text.split(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '(' | ')' | '`' | '*'))
+        .map(|word| word.trim_end_matches(['.', ':']))
+        .filter(|word| {
+            let digits = word.strip_prefix('v').unwrap_or(word);
+            digits.starts_with(|c: char| c.is_ascii_digit())
+                && digits.contains(['.', '-', '/'])
+                && digits
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '/'))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use context_server::{Tool, ToolContent};
+
+    use super::*;
+
+    const MIN_SIMILARITY: f32 = 0.9;
+
+    struct FakeSearch;
+
+    #[async_trait]
+    impl ToolExecutor for FakeSearch {
+        async fn execute(&self, _arguments: Option<Value>) -> Result<Vec<ToolContent>> {
+            Ok(vec![ToolContent::Text {
+                text: "Tokio 1.42.0 is the latest release.".into(),
+            }])
+        }
+
+        fn to_tool(&self) -> Tool {
+            Tool {
+                name: "search".into(),
+                description: None,
+                input_schema: json!({}),
+            }
+        }
+    }
+
+    fn client(name: &str) -> ToolCallContext {
+        ToolCallContext {
+            request_id: None,
+            client_name: Some(name.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_forgets_the_subscriber() -> Result<()> {
+        let config = WatchConfig {
+            topics: HashMap::from([(
+                "tokio".to_string(),
+                WatchTopic {
+                    query: "Latest Tokio release".into(),
+                    tool: default_tool(),
+                    interval_minutes: None,
+                },
+            )]),
+            ..WatchConfig::default()
+        };
+        let watches = Arc::new(Watches::new(
+            config,
+            vec![Arc::new(FakeSearch)],
+            None,
+            Arc::new(|_: &str| {}),
+        ));
+
+        assert!(watches.subscribe("tokio", &client("zed"))?);
+        watches.unsubscribe("tokio")?;
+        assert!(!watches.lock()?.contains_key("tokio"));
+
+        assert!(watches.subscribe("tokio", &client("claude"))?);
+        let client_name = watches.lock()?["tokio"].context.client_name.clone();
+        assert_eq!(client_name.as_deref(), Some("claude"));
+        Ok(())
+    }
+
+    #[test]
+    fn new_references_are_not_a_change() {
+        let previous = "Tokio 1.42.0 is the latest release.\n\nReferences:\n[1] https://a.example";
+        let current = "Tokio 1.42.0 is the latest release.\n\nReferences:\n[1] https://b.example";
+        assert!(!differs(previous, current, MIN_SIMILARITY));
+    }
+
+    #[test]
+    fn reasoning_is_not_a_change() {
+        let previous =
+            "<think>Let me look up the releases.</think>\nTokio 1.42.0 is the latest release.";
+        let current = "<think>The changelog lists 1.41.1 and then 1.42.0, so the latest is 1.42.0.</think>\nTokio 1.42.0 is the latest release.";
+        assert!(!differs(previous, current, MIN_SIMILARITY));
+    }
+
+    #[test]
+    fn new_versions_and_dates_are_a_change() {
+        assert!(differs(
+            "Tokio 1.42.0 is the latest release.",
+            "Tokio 1.43.0 is the latest release.",
+            MIN_SIMILARITY
+        ));
+        assert!(differs(
+            "Rust 1.90 is due on 2025-09-18.",
+            "Rust 1.90 is due on 2025-09-25.",
+            MIN_SIMILARITY
+        ));
+    }
+
+    #[test]
+    fn different_answers_are_a_change() {
+        assert!(differs(
+            "The proposal is still under discussion and has no release date.",
+            "The feature was stabilised and ships in the next stable release of the compiler.",
+            MIN_SIMILARITY
+        ));
+    }
+
+    #[test]
+    fn unfinished_reasoning_is_kept() {
+        assert_eq!(substance("<think>Still thinking"), "<think>Still thinking");
+    }
+}